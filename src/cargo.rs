@@ -0,0 +1,311 @@
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+/// The subset of `cargo metadata` output used to find binaries and services
+#[derive(Deserialize)]
+pub struct Metadata {
+    pub packages: Vec<Package>,
+    pub workspace_root: PathBuf,
+    pub target_directory: PathBuf,
+}
+
+#[derive(Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub manifest_path: PathBuf,
+    pub targets: Vec<Target>,
+    #[serde(default)]
+    pub dependencies: Vec<Dependency>,
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+pub struct Dependency {
+    /// Only set for path dependencies
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+pub struct Target {
+    pub name: String,
+    pub kind: Vec<String>,
+}
+
+impl Target {
+    pub fn is_bin(&self) -> bool {
+        self.kind.iter().any(|kind| kind == "bin")
+    }
+}
+
+// Which Cargo binary to build and run, given on the command line
+#[derive(StructOpt, Debug)]
+pub struct CargoArgs {
+    /// Build and run this binary target of the current Cargo project
+    #[structopt(long)]
+    pub bin: Option<String>,
+    /// Build and run every binary target of the workspace, each as its own service
+    #[structopt(long, conflicts_with_all = &["bin", "package", "name"])]
+    pub workspace: bool,
+    /// Leave out this package with --workspace (can be repeated)
+    #[structopt(long, number_of_values = 1, requires = "workspace")]
+    pub exclude: Vec<String>,
+    /// The package the binary belongs to
+    #[structopt(short, long)]
+    pub package: Option<String>,
+    /// Build with the release profile
+    #[structopt(long, conflicts_with = "profile")]
+    pub release: bool,
+    /// Build with the given profile
+    #[structopt(long)]
+    pub profile: Option<String>,
+    /// Features to enable, comma or space separated (can be repeated)
+    #[structopt(long, number_of_values = 1)]
+    pub features: Vec<String>,
+    /// The Cargo.toml to use instead of the one of the current directory
+    #[structopt(long, parse(from_os_str))]
+    pub manifest_path: Option<PathBuf>,
+    /// Rebuild and restart the service whenever its sources change
+    #[structopt(long, requires = "bin")]
+    pub watch: bool,
+}
+
+/// A Cargo binary a service runs, recorded so that it can be rebuilt
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CargoTarget {
+    pub manifest_path: PathBuf,
+    pub package: String,
+    pub bin: String,
+    pub profile: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+/// The arguments for building all `targets` in one `cargo build`. They are
+/// expected to share their manifest, profile and features.
+fn build_args(targets: &[CargoTarget]) -> Vec<OsString> {
+    let first = &targets[0];
+    let mut args: Vec<OsString> = vec![
+        "build".into(),
+        "--manifest-path".into(),
+        first.manifest_path.clone().into(),
+        "--profile".into(),
+        first.profile.clone().into(),
+    ];
+    if !first.features.is_empty() {
+        args.push("--features".into());
+        args.push(first.features.join(",").into());
+    }
+    for target in targets {
+        args.extend(["--package".into(), target.package.clone().into()]);
+        args.extend(["--bin".into(), target.bin.clone().into()]);
+    }
+    args
+}
+
+fn cargo_command() -> Command {
+    Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+}
+
+/// Runs `cargo metadata` for the workspace of `manifest_path`, or of the current directory
+pub fn metadata(manifest_path: Option<&Path>) -> Result<Metadata, String> {
+    let mut command = cargo_command();
+    command.args(["metadata", "--format-version", "1", "--no-deps"]);
+    if let Some(manifest_path) = manifest_path {
+        command.arg("--manifest-path").arg(manifest_path);
+    }
+    let output = command.output().map_err(|e| format!("Failed to run cargo metadata: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| format!("Invalid cargo metadata output: {}", e))
+}
+
+/// The directory under the target directory that a profile builds into
+fn profile_dir(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        other => other,
+    }
+}
+
+impl CargoArgs {
+    fn profile(&self) -> String {
+        match (&self.profile, self.release) {
+            (Some(profile), _) => profile.clone(),
+            (None, true) => "release".to_string(),
+            (None, false) => "dev".to_string(),
+        }
+    }
+
+    fn features(&self) -> Vec<String> {
+        self.features
+            .iter()
+            .flat_map(|features| features.split([',', ' ']))
+            .filter(|feature| !feature.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Finds the package that has the binary target `bin`
+    pub fn resolve(&self, metadata: &Metadata, bin: &str) -> Result<CargoTarget, String> {
+        let candidates: Vec<&Package> = metadata
+            .packages
+            .iter()
+            .filter(|package| self.package.as_ref().is_none_or(|name| &package.name == name))
+            .filter(|package| package.targets.iter().any(|t| t.is_bin() && t.name == bin))
+            .collect();
+        let package = match candidates.as_slice() {
+            [package] => package,
+            [] => return Err(format!("No binary target named {} found", bin)),
+            _ => {
+                return Err(format!(
+                    "Several packages have a binary named {}, select one with --package",
+                    bin
+                ))
+            }
+        };
+
+        Ok(self.target(metadata, package, bin))
+    }
+
+    fn target(&self, metadata: &Metadata, package: &Package, bin: &str) -> CargoTarget {
+        CargoTarget {
+            manifest_path: metadata.workspace_root.join("Cargo.toml"),
+            package: package.name.clone(),
+            bin: bin.to_string(),
+            profile: self.profile(),
+            features: self.features(),
+        }
+    }
+
+    /// Every binary target of the workspace members that are not excluded
+    pub fn resolve_workspace(&self, metadata: &Metadata) -> Result<Vec<CargoTarget>, String> {
+        let targets: Vec<CargoTarget> = metadata
+            .packages
+            .iter()
+            .filter(|package| !self.exclude.contains(&package.name))
+            .flat_map(|package| {
+                package
+                    .targets
+                    .iter()
+                    .filter(|target| target.is_bin())
+                    .map(move |target| self.target(metadata, package, &target.name))
+            })
+            .collect();
+        // Cargo builds binaries of the same name to the same file, so only one of them could run
+        for (index, target) in targets.iter().enumerate() {
+            let packages: Vec<&str> = targets[index..]
+                .iter()
+                .filter(|other| other.bin == target.bin)
+                .map(|other| other.package.as_str())
+                .collect();
+            if packages.len() > 1 {
+                return Err(format!(
+                    "Packages {} all have a binary named {}, which Cargo builds to the same file. \
+                     Leave all but one of them out with --exclude.",
+                    packages.join(", "),
+                    target.bin
+                ));
+            }
+        }
+        Ok(targets)
+    }
+}
+
+/// Where `cargo build` puts the binary of a target
+pub fn binary_path(metadata: &Metadata, target: &CargoTarget) -> PathBuf {
+    metadata
+        .target_directory
+        .join(profile_dir(&target.profile))
+        .join(&target.bin)
+}
+
+/// Builds the targets with one `cargo build`, its output going to our terminal
+pub fn build(targets: &[CargoTarget]) -> Result<(), String> {
+    if targets.is_empty() {
+        return Ok(());
+    }
+    let status = cargo_command()
+        .args(build_args(targets))
+        .status()
+        .map_err(|e| format!("Failed to run cargo build: {}", e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        let bins: Vec<&str> = targets.iter().map(|target| target.bin.as_str()).collect();
+        Err(format!("Building {} failed", bins.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str, targets: &[(&str, &str)]) -> Package {
+        Package {
+            name: name.to_string(),
+            manifest_path: PathBuf::from(format!("/ws/{}/Cargo.toml", name)),
+            targets: targets
+                .iter()
+                .map(|(name, kind)| Target {
+                    name: name.to_string(),
+                    kind: vec![kind.to_string()],
+                })
+                .collect(),
+            dependencies: Vec::new(),
+            metadata: None,
+        }
+    }
+
+    /// Two packages with a binary named `server`, and a library
+    fn workspace() -> Metadata {
+        Metadata {
+            packages: vec![
+                package("api", &[("server", "bin"), ("api", "lib")]),
+                package("admin", &[("server", "bin"), ("migrate", "bin")]),
+                package("common", &[("common", "lib")]),
+            ],
+            workspace_root: PathBuf::from("/ws"),
+            target_directory: PathBuf::from("/ws/target"),
+        }
+    }
+
+    fn workspace_args(exclude: &[&str]) -> CargoArgs {
+        CargoArgs {
+            bin: None,
+            workspace: true,
+            exclude: exclude.iter().map(|name| name.to_string()).collect(),
+            package: None,
+            release: false,
+            profile: None,
+            features: Vec::new(),
+            manifest_path: None,
+            watch: false,
+        }
+    }
+
+    #[test]
+    fn binaries_of_the_same_name_are_rejected() {
+        let error = workspace_args(&[]).resolve_workspace(&workspace()).unwrap_err();
+        assert!(error.starts_with("Packages api, admin all have a binary named server"), "{}", error);
+    }
+
+    #[test]
+    fn excluded_packages_are_left_out() {
+        let targets = workspace_args(&["api"]).resolve_workspace(&workspace()).unwrap();
+        let bins: Vec<(&str, &str)> = targets.iter().map(|t| (t.package.as_str(), t.bin.as_str())).collect();
+        assert_eq!(bins, [("admin", "server"), ("admin", "migrate")]);
+        assert_eq!(targets[0].manifest_path, Path::new("/ws/Cargo.toml"));
+        assert_eq!(targets[0].profile, "dev");
+    }
+}
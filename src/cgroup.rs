@@ -0,0 +1,121 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::process;
+
+/// Where the cgroup v2 hierarchy is mounted, `/sys/fs/cgroup` on most systems
+/// and `/sys/fs/cgroup/unified` on hybrid ones
+fn mount_point() -> io::Result<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    mountinfo
+        .lines()
+        .find_map(|line| {
+            let (mount, filesystem) = line.split_once(" - ")?;
+            if filesystem.split_whitespace().next()? != "cgroup2" {
+                return None;
+            }
+            mount.split_whitespace().nth(4).map(PathBuf::from)
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No cgroup v2 hierarchy is mounted"))
+}
+
+/// The cgroup v2 directory of the current process
+fn own_cgroup() -> io::Result<PathBuf> {
+    let cgroup = fs::read_to_string("/proc/self/cgroup")?;
+    let path = cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Not in a cgroup v2 hierarchy"))?;
+    Ok(mount_point()?.join(path.trim_start_matches('/')))
+}
+
+/// Where the supervisor moves itself to when it has to make room for controllers
+const SUPERVISOR_LEAF: &str = "cargo-service.supervisor";
+
+/// The cgroup the cgroups of services are created in, the one the supervisor
+/// was started in
+fn base() -> io::Result<PathBuf> {
+    let own = own_cgroup()?;
+    match own.parent() {
+        Some(parent) if own.ends_with(SUPERVISOR_LEAF) => Ok(parent.to_path_buf()),
+        _ => Ok(own),
+    }
+}
+
+/// Moves the supervisor into a leaf below the cgroup it was started in.
+/// Controllers can only be handed down by a cgroup without processes of its
+/// own, so this has to happen before the supervisor forks anything, which then
+/// starts in the leaf as well.
+pub fn enter_leaf() -> io::Result<()> {
+    let own = own_cgroup()?;
+    // The root cgroup is exempt from that rule
+    if own.ends_with(SUPERVISOR_LEAF) || own == mount_point()? {
+        return Ok(());
+    }
+    let leaf = own.join(SUPERVISOR_LEAF);
+    if let Err(e) = fs::create_dir(&leaf) {
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(e);
+        }
+    }
+    fs::write(leaf.join("cgroup.procs"), "0")
+}
+
+/// Makes `controllers` such as `memory` available to the cgroups of services
+pub fn enable_controllers(controllers: &[&str]) -> io::Result<()> {
+    let base = base()?;
+    let control: Vec<String> = controllers.iter().map(|c| format!("+{}", c)).collect();
+    fs::write(base.join("cgroup.subtree_control"), control.join(" ")).map_err(|e| {
+        if e.raw_os_error() == Some(libc::EBUSY) {
+            io::Error::new(
+                e.kind(),
+                format!("{} has processes other than the supervisor in it", base.display()),
+            )
+        } else {
+            e
+        }
+    })
+}
+
+/// Writes a control file like `memory.max` of a cgroup
+pub fn set(path: &Path, control: &str, value: &str) -> io::Result<()> {
+    fs::write(path.join(control), value)
+}
+
+/// Creates the cgroup of a service below the one the supervisor was started in
+pub fn create(name: &str) -> io::Result<PathBuf> {
+    let path = base()?.join(format!("cargo-service.{}", name));
+    match fs::create_dir(&path) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(path),
+    }
+}
+
+/// Opens the file that moves a process into the cgroup when its pid, or `0`
+/// for the writing process itself, is written to it
+pub fn open_procs(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).open(path.join("cgroup.procs"))
+}
+
+/// The pids of every process in the cgroup
+pub fn members(path: &Path) -> Vec<u32> {
+    fs::read_to_string(path.join("cgroup.procs"))
+        .map(|procs| procs.lines().filter_map(|pid| pid.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Sends SIGKILL to every process in the cgroup
+pub fn kill(path: &Path) {
+    // cgroup.kill only exists since Linux 5.14
+    if fs::write(path.join("cgroup.kill"), "1").is_err() {
+        for pid in members(path) {
+            let _ = process::send_signal(pid, libc::SIGKILL);
+        }
+    }
+}
+
+/// Removes the cgroup, which only works once it is empty
+pub fn remove(path: &Path) {
+    let _ = fs::remove_dir(path);
+}
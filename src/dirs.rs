@@ -0,0 +1,152 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::error::{Error, Result};
+use crate::user;
+
+/// Where a system-wide supervisor keeps its state
+pub const SYSTEM_STATE_DIR: &str = "/var/lib/cargo-service";
+const SYSTEM_RUNTIME_DIR: &str = "/run/cargo-service";
+
+/// Overrides every other location, for isolated registries in tests and CI
+const HOME_VAR: &str = "CARGO_SERVICE_HOME";
+
+/// The runtime dir below a state dir, which may be a directory of the user
+/// that must keep its permissions
+const RUN_SUBDIR: &str = "run";
+
+struct Dirs {
+    /// The registry and the logs
+    state: PathBuf,
+    /// The socket, pid and lock files of the supervisor
+    runtime: PathBuf,
+    /// Make the supervisor use the same directories
+    supervisor_args: Vec<OsString>,
+}
+
+static DIRS: OnceLock<Dirs> = OnceLock::new();
+
+/// Picks and creates the directories, must be called before anything uses them.
+/// `--state-dir` wins over `--system`, which wins over `CARGO_SERVICE_HOME`,
+/// which wins over the XDG base directories.
+pub fn init(state_dir: Option<PathBuf>, system: bool) -> Result<()> {
+    let home = state_dir.or_else(|| {
+        if system {
+            return None;
+        }
+        env::var_os(HOME_VAR).filter(|home| !home.is_empty()).map(PathBuf::from)
+    });
+    let dirs = match home {
+        Some(home) => {
+            // The supervisor may be started from another directory
+            let home = env::current_dir()
+                .map_err(|e| Error::io("Failed to get the current directory", e))?
+                .join(home);
+            Dirs {
+                state: home.clone(),
+                runtime: home.join(RUN_SUBDIR),
+                supervisor_args: vec!["--state-dir".into(), home.into()],
+            }
+        }
+        None if system => Dirs {
+            state: PathBuf::from(SYSTEM_STATE_DIR),
+            runtime: PathBuf::from(SYSTEM_RUNTIME_DIR),
+            supervisor_args: vec!["--system".into()],
+        },
+        None => {
+            let home = user::home_dir()
+                .ok_or_else(|| Error::Other("Failed to find the home directory".to_string()))?;
+            let state = xdg_dir("XDG_STATE_HOME", &home, ".local/state").join("cargo-service");
+            // Without a runtime directory the files have to survive in the state directory
+            let runtime =
+                xdg_var("XDG_RUNTIME_DIR").map_or_else(|| state.join(RUN_SUBDIR), |dir| dir.join("cargo-service"));
+            migrate_legacy(&xdg_dir("XDG_CONFIG_HOME", &home, ".config").join("cargo-service"), &state)?;
+            Dirs {
+                state,
+                runtime,
+                supervisor_args: Vec::new(),
+            }
+        }
+    };
+
+    // The registry may hold secrets given with --env
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dirs.state)
+        .map_err(|e| Error::io(format!("Failed to create {}", dirs.state.display()), e))?;
+    // Only the owner may talk to the supervisor. The runtime dir is always one
+    // of our own, so its mode can be enforced even when it already exists.
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dirs.runtime)
+        .and_then(|_| fs::set_permissions(&dirs.runtime, fs::Permissions::from_mode(0o700)))
+        .map_err(|e| Error::io(format!("Failed to create {}", dirs.runtime.display()), e))?;
+    let _ = DIRS.set(dirs);
+    Ok(())
+}
+
+/// An XDG base directory from its variable, which only counts when absolute
+fn xdg_var(var: &str) -> Option<PathBuf> {
+    env::var_os(var).map(PathBuf::from).filter(|dir| dir.is_absolute())
+}
+
+fn xdg_dir(var: &str, home: &Path, default: &str) -> PathBuf {
+    xdg_var(var).unwrap_or_else(|| home.join(default))
+}
+
+/// Moves the registry out of the config directory, where older versions kept
+/// everything. The logs stay where they are, their paths are in the registry.
+fn migrate_legacy(legacy: &Path, state: &Path) -> Result<()> {
+    let registry = legacy.join("cache.ron");
+    if !registry.exists() || state.join("cache.ron").exists() {
+        return Ok(());
+    }
+    if UnixStream::connect(legacy.join("supervisor.sock")).is_ok() {
+        eprintln!(
+            "A supervisor of an older version is still running from {}, stop it to move its registry to {}",
+            legacy.display(),
+            state.display()
+        );
+        return Ok(());
+    }
+    fs::create_dir_all(state).map_err(|e| Error::io(format!("Failed to create {}", state.display()), e))?;
+    for file in ["cache.ron.bak", "cache.ron"] {
+        let from = legacy.join(file);
+        if !from.exists() {
+            continue;
+        }
+        let to = state.join(file);
+        // Renaming fails across file systems
+        fs::rename(&from, &to)
+            .or_else(|_| fs::copy(&from, &to).and_then(|_| fs::remove_file(&from)))
+            .map_err(|e| Error::io(format!("Failed to move {} to {}", from.display(), to.display()), e))?;
+    }
+    eprintln!("Moved the registry from {} to {}", legacy.display(), state.display());
+    Ok(())
+}
+
+fn dirs() -> &'static Dirs {
+    DIRS.get().expect("Directories used before they were created")
+}
+
+/// The directory holding the registry and everything else the services produce
+pub fn get_state_dir() -> PathBuf {
+    dirs().state.clone()
+}
+
+/// The directory holding the socket, pid and lock files of the supervisor
+pub fn get_runtime_dir() -> PathBuf {
+    dirs().runtime.clone()
+}
+
+/// The arguments that make a supervisor use the same directories as this process
+pub fn supervisor_args() -> &'static [OsString] {
+    &dirs().supervisor_args
+}
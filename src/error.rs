@@ -0,0 +1,113 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+
+/// Shown at the end of `--help`, keep in sync with [`Error::exit_code`]
+pub const EXIT_CODES: &str = "EXIT CODES:
+    0    Success
+    1    Any other failure
+    2    Invalid manifest, arguments or dependencies
+    3    No such service
+    4    The service is already registered
+    5    The service could not be spawned
+    6    A state file is corrupt
+    7    Permission denied
+    8    The service or one of its dependencies is not ready
+    9    The supervisor could not be reached";
+
+/// Everything that can go wrong, sent from the supervisor to the CLI as is
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Error {
+    /// No service with this name is registered
+    NotFound(String),
+    /// A service with this name is already registered
+    AlreadyRunning(String),
+    SpawnFailed { name: String, reason: String },
+    /// A state file could not be parsed
+    StateCorrupt { path: PathBuf, reason: String },
+    PermissionDenied(String),
+    /// A manifest, argument or dependency that makes no sense
+    Invalid(String),
+    NotReady(String),
+    Supervisor(String),
+    Other(String),
+    /// Several operations failed, e.g. when starting a whole workspace
+    Multiple(Vec<Error>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Wraps an I/O error with what was being done when it happened
+    pub fn io(context: impl fmt::Display, error: io::Error) -> Error {
+        let message = format!("{}: {}", context, error);
+        if error.kind() == io::ErrorKind::PermissionDenied {
+            Error::PermissionDenied(message)
+        } else {
+            Error::Other(message)
+        }
+    }
+
+    /// The exit code documented in [`EXIT_CODES`]
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Other(_) => 1,
+            Error::Invalid(_) => 2,
+            Error::NotFound(_) => 3,
+            Error::AlreadyRunning(_) => 4,
+            Error::SpawnFailed { .. } => 5,
+            Error::StateCorrupt { .. } => 6,
+            Error::PermissionDenied(_) => 7,
+            Error::NotReady(_) => 8,
+            Error::Supervisor(_) => 9,
+            // Only specific when every failure was the same
+            Error::Multiple(errors) => {
+                let first = errors.first().map_or(1, Error::exit_code);
+                if errors.iter().all(|e| e.exit_code() == first) {
+                    first
+                } else {
+                    1
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound(name) => write!(f, "Service {} not found", name),
+            Error::AlreadyRunning(name) => write!(f, "Service {} already exists", name),
+            Error::SpawnFailed { name, reason } => {
+                write!(f, "Failed to start service {}: {}", name, reason)
+            }
+            Error::StateCorrupt { path, reason } => write!(
+                f,
+                "{} is corrupt: {}\nMove it away to start over without the services in it",
+                path.display(),
+                reason
+            ),
+            Error::PermissionDenied(message) => write!(f, "Permission denied: {}", message),
+            Error::Invalid(message)
+            | Error::NotReady(message)
+            | Error::Supervisor(message)
+            | Error::Other(message) => write!(f, "{}", message),
+            Error::Multiple(errors) => {
+                let messages: Vec<String> = errors.iter().map(Error::to_string).collect();
+                write!(f, "{}", messages.join("\n"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Turns the failures of a batch of operations into one error, if any
+pub fn collect(mut errors: Vec<Error>) -> Result<()> {
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ => Err(Error::Multiple(errors)),
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use crate::service::Service;
+
+/// Services by name with the names of the services they depend on
+pub type Nodes<'a> = Vec<(&'a str, &'a [String])>;
+
+pub fn nodes(services: &[Service]) -> Nodes<'_> {
+    services
+        .iter()
+        .map(|service| (service.name.as_str(), service.depends_on.as_slice()))
+        .collect()
+}
+
+/// Orders services so that every service comes after the services it depends
+/// on. Dependencies outside of `services` are ignored, the caller checks them.
+pub fn topological_order<'a>(services: &[(&'a str, &'a [String])]) -> Result<Vec<&'a str>, String> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Mark {
+        Visiting,
+        Done,
+    }
+
+    fn visit<'a>(
+        name: &'a str,
+        deps: &HashMap<&'a str, &'a [String]>,
+        marks: &mut HashMap<&'a str, Mark>,
+        path: &mut Vec<&'a str>,
+        order: &mut Vec<&'a str>,
+    ) -> Result<(), String> {
+        match marks.get(name) {
+            Some(Mark::Done) => return Ok(()),
+            Some(Mark::Visiting) => {
+                let start = path.iter().position(|n| *n == name).unwrap_or(0);
+                let mut cycle = path[start..].to_vec();
+                cycle.push(name);
+                return Err(format!("Dependency cycle: {}", cycle.join(" -> ")));
+            }
+            None => {}
+        }
+        marks.insert(name, Mark::Visiting);
+        path.push(name);
+        for dep in deps[name].iter() {
+            if let Some((dep, _)) = deps.get_key_value(dep.as_str()) {
+                visit(dep, deps, marks, path, order)?;
+            }
+        }
+        path.pop();
+        marks.insert(name, Mark::Done);
+        order.push(name);
+        Ok(())
+    }
+
+    let deps: HashMap<&str, &[String]> = services.iter().copied().collect();
+    let mut marks = HashMap::new();
+    let mut order = Vec::new();
+    for (name, _) in services {
+        visit(name, &deps, &mut marks, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+/// `name` and every service that directly or indirectly depends on it
+pub fn with_dependents<'a>(name: &'a str, services: &[(&'a str, &'a [String])]) -> Vec<&'a str> {
+    let mut found = vec![name];
+    let mut index = 0;
+    while index < found.len() {
+        let current = found[index];
+        for (dependent, deps) in services {
+            if deps.iter().any(|dep| dep == current) && !found.contains(dependent) {
+                found.push(dependent);
+            }
+        }
+        index += 1;
+    }
+    found
+}
+
+/// Renders the dependency graph in Graphviz DOT, with edges pointing from a
+/// service to the services it depends on
+pub fn to_dot(services: &[(&str, &[String])]) -> String {
+    let mut dot = String::from("digraph services {\n");
+    for (name, deps) in services {
+        dot.push_str(&format!("    {:?};\n", name));
+        for dep in deps.iter() {
+            dot.push_str(&format!("    {:?} -> {:?};\n", name, dep));
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deps(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let (web, worker, db, cache) = (deps(&["db", "cache"]), deps(&["db"]), deps(&[]), deps(&["db"]));
+        let services: Nodes = vec![("web", &web), ("worker", &worker), ("db", &db), ("cache", &cache)];
+        let order = topological_order(&services).unwrap();
+        let position = |name| order.iter().position(|n| *n == name).unwrap();
+        assert_eq!(order.len(), 4);
+        assert!(position("db") < position("cache"));
+        assert!(position("cache") < position("web"));
+        assert!(position("db") < position("worker"));
+    }
+
+    #[test]
+    fn unknown_dependencies_are_ignored() {
+        let web = deps(&["elsewhere"]);
+        let services: Nodes = vec![("web", &web)];
+        assert_eq!(topological_order(&services).unwrap(), vec!["web"]);
+    }
+
+    #[test]
+    fn cycles_are_reported_with_their_path() {
+        let (a, b, c) = (deps(&["b"]), deps(&["c"]), deps(&["a"]));
+        let services: Nodes = vec![("a", &a), ("b", &b), ("c", &c)];
+        assert_eq!(topological_order(&services).unwrap_err(), "Dependency cycle: a -> b -> c -> a");
+    }
+
+    #[test]
+    fn self_dependency_is_a_cycle() {
+        let a = deps(&["a"]);
+        let services: Nodes = vec![("a", &a)];
+        assert_eq!(topological_order(&services).unwrap_err(), "Dependency cycle: a -> a");
+    }
+
+    #[test]
+    fn dependents_are_found_transitively() {
+        let (db, api, web, other) = (deps(&[]), deps(&["db"]), deps(&["api"]), deps(&[]));
+        let services: Nodes = vec![("db", &db), ("api", &api), ("web", &web), ("other", &other)];
+        assert_eq!(with_dependents("db", &services), vec!["db", "api", "web"]);
+        assert_eq!(with_dependents("web", &services), vec!["web"]);
+    }
+}
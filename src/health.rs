@@ -0,0 +1,229 @@
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::logs::{self, LogFiles};
+use crate::time::parse_duration;
+
+/// A probe telling whether a service works
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Check {
+    /// A connection to this address, or to this port on localhost, succeeds
+    Tcp(String),
+    /// A GET request to this `http://` URL returns a 2xx status
+    Http(String),
+    /// This shell command exits with 0
+    Command(String),
+    /// The service printed a line matching this regex
+    Log(String),
+}
+
+impl FromStr for Check {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("http://") {
+            return Ok(Check::Http(s.to_string()));
+        }
+        match s.split_once(':') {
+            Some(("tcp", address)) => Ok(Check::Tcp(address.to_string())),
+            Some(("cmd", command)) => Ok(Check::Command(command.to_string())),
+            Some(("log", pattern)) => Regex::new(pattern)
+                .map(|_| Check::Log(pattern.to_string()))
+                .map_err(|e| format!("Invalid regex {}: {}", pattern, e)),
+            _ => Err(format!(
+                "Unknown check {}, expected tcp:PORT, tcp:HOST:PORT, http://URL, cmd:COMMAND or log:REGEX",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Check::Tcp(address) => write!(f, "tcp:{}", address),
+            Check::Http(url) => write!(f, "{}", url),
+            Check::Command(command) => write!(f, "cmd:{}", command),
+            Check::Log(pattern) => write!(f, "log:{}", pattern),
+        }
+    }
+}
+
+// The health checks of a service and how they are run
+#[derive(StructOpt, Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HealthChecks {
+    /// Check that has to pass before the service counts as ready:
+    /// tcp:PORT, tcp:HOST:PORT, http://URL, cmd:COMMAND or log:REGEX
+    #[structopt(long = "ready")]
+    pub readiness: Option<Check>,
+    /// Check run periodically once the service is ready, in the same format as --ready
+    #[structopt(long = "live")]
+    pub liveness: Option<Check>,
+    /// How often the liveness check runs
+    #[structopt(long = "health-interval", default_value = "10s", parse(try_from_str = parse_duration))]
+    pub interval: Duration,
+    /// How long a single check may take
+    #[structopt(long = "health-timeout", default_value = "5s", parse(try_from_str = parse_duration))]
+    pub timeout: Duration,
+    /// Liveness failures in a row before the service is restarted
+    #[structopt(long = "health-retries", default_value = "3")]
+    pub retries: u32,
+    /// How long the service may take to pass its readiness check
+    #[structopt(long = "ready-timeout", default_value = "60s", parse(try_from_str = parse_duration))]
+    pub ready_timeout: Duration,
+}
+
+impl Default for HealthChecks {
+    fn default() -> Self {
+        HealthChecks {
+            readiness: None,
+            liveness: None,
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+            retries: 3,
+            ready_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl HealthChecks {
+    pub fn any(&self) -> bool {
+        self.readiness.is_some() || self.liveness.is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HealthState {
+    /// The service has no health checks
+    #[default]
+    Unchecked,
+    /// Waiting for the readiness check to pass
+    Starting,
+    Ready,
+    /// Failed its checks and is being killed
+    Unhealthy,
+}
+
+impl HealthState {
+    pub fn label(self) -> &'static str {
+        match self {
+            HealthState::Unchecked => "unchecked",
+            HealthState::Starting => "starting",
+            HealthState::Ready => "ready",
+            HealthState::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct Health {
+    pub state: HealthState,
+    /// Liveness checks failed in a row
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+
+impl Health {
+    /// Whether services depending on this one may be started
+    pub fn is_ready(&self) -> bool {
+        matches!(self.state, HealthState::Unchecked | HealthState::Ready)
+    }
+}
+
+/// What a check needs to know about the service it probes
+pub struct Probe<'a> {
+    pub logs: Option<&'a LogFiles>,
+    /// Log lines written before this do not count
+    pub since: SystemTime,
+    pub timeout: Duration,
+}
+
+impl Check {
+    /// Runs the check. Commands are handed to `run_command` because the
+    /// supervisor has to reap them itself.
+    pub fn probe(
+        &self,
+        probe: &Probe,
+        run_command: impl FnOnce(&str) -> Result<(), String>,
+    ) -> Result<(), String> {
+        match self {
+            Check::Tcp(address) => {
+                connect(&local_address(address, None), probe.timeout).map(|_| ())
+            }
+            Check::Http(url) => http_get(url, probe.timeout),
+            Check::Command(command) => run_command(command),
+            Check::Log(pattern) => {
+                let regex = Regex::new(pattern).map_err(|e| format!("Invalid regex {}: {}", pattern, e))?;
+                let logs = probe.logs.ok_or("The service has no log files")?;
+                match logs::has_line_since(logs, probe.since, |line| regex.is_match(line)) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err(format!("No line matching {} was logged", pattern)),
+                    Err(e) => Err(format!("Failed to read logs: {}", e)),
+                }
+            }
+        }
+    }
+}
+
+/// A bare port means that port on localhost
+fn local_address(address: &str, default_port: Option<u16>) -> String {
+    if address.parse::<u16>().is_ok() {
+        format!("127.0.0.1:{}", address)
+    } else if let (false, Some(port)) = (address.contains(':'), default_port) {
+        format!("{}:{}", address, port)
+    } else {
+        address.to_string()
+    }
+}
+
+fn connect(address: &str, timeout: Duration) -> Result<TcpStream, String> {
+    let addr = address
+        .to_socket_addrs()
+        .map_err(|e| format!("Invalid address {}: {}", address, e))?
+        .next()
+        .ok_or_else(|| format!("{} did not resolve to an address", address))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)
+        .map_err(|e| format!("Failed to connect to {}: {}", address, e))?;
+    stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+/// Sends a plain HTTP/1.0 GET and checks the status line for a 2xx code
+fn http_get(url: &str, timeout: Duration) -> Result<(), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("Only http:// URLs can be checked, got {}", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let mut stream = connect(&local_address(host, Some(80)), timeout)?;
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host);
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| format!("Failed to send request to {}: {}", url, e))?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status_line)
+        .map_err(|e| format!("Failed to read response from {}: {}", url, e))?;
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("Invalid response from {}", url))?;
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(format!("{} returned {}", url, status_line.trim_end()))
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use ron::de::from_reader;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+
+use crate::dirs::get_state_dir;
+use crate::error::{Error, Result};
+use crate::protocol::ExitInfo;
+
+/// Runs kept per service, older ones are dropped
+const MAX_RUNS: usize = 100;
+
+/// Why the supervisor spawned a process
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartReason {
+    /// Started with `start` or `up`
+    Start,
+    /// Restarted with `restart` or by `watch`
+    Restart,
+    /// Restarted by the restart policy after the previous run exited
+    Exited,
+    /// Restarted after the previous run failed its liveness check
+    Unhealthy,
+}
+
+impl StartReason {
+    pub fn label(self) -> &'static str {
+        match self {
+            StartReason::Start => "start",
+            StartReason::Restart => "restart",
+            StartReason::Exited => "exited",
+            StartReason::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+/// One process of a service, from spawn to exit
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Run {
+    pub pid: u32,
+    pub reason: StartReason,
+    pub started: SystemTime,
+    /// `None` while the process is running
+    pub stopped: Option<SystemTime>,
+    /// Only known for processes of the supervisor that is running
+    pub exit: Option<ExitInfo>,
+}
+
+fn get_history_path(name: &str) -> PathBuf {
+    get_state_dir().join("history").join(format!("{}.ron", name))
+}
+
+/// The runs of a service, oldest first, `None` if it never ran
+pub fn load(name: &str) -> Result<Option<Vec<Run>>> {
+    let path = get_history_path(name);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::io(format!("Failed to open {}", path.display()), e)),
+    };
+    from_reader(file).map(Some).map_err(|e| Error::StateCorrupt {
+        path,
+        reason: e.to_string(),
+    })
+}
+
+/// Written by the supervisor only, the rename keeps readers from seeing half of it
+fn save(name: &str, runs: &[Run]) -> Result<()> {
+    let path = get_history_path(name);
+    let data = to_string_pretty(runs, PrettyConfig::new())
+        .map_err(|e| Error::Other(format!("Failed to serialize history: {}", e)))?;
+    let tmp = path.with_extension("ron.tmp");
+    let write = || -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        File::create(&tmp)?.write_all(data.as_bytes())?;
+        fs::rename(&tmp, &path)
+    };
+    write().map_err(|e| Error::io(format!("Failed to write {}", path.display()), e))
+}
+
+pub fn record_start(name: &str, pid: u32, reason: StartReason) -> Result<()> {
+    // A corrupt history is started over rather than keeping the service from running
+    let mut runs = load(name).unwrap_or_default().unwrap_or_default();
+    runs.push(Run {
+        pid,
+        reason,
+        started: SystemTime::now(),
+        stopped: None,
+        exit: None,
+    });
+    let excess = runs.len().saturating_sub(MAX_RUNS);
+    runs.drain(..excess);
+    save(name, &runs)
+}
+
+/// Completes the run of `pid`, unless that already happened
+pub fn record_exit(name: &str, pid: u32, exit: Option<ExitInfo>) -> Result<()> {
+    let Some(mut runs) = load(name)? else {
+        return Ok(());
+    };
+    let Some(run) = runs.iter_mut().rev().find(|run| run.pid == pid && run.stopped.is_none()) else {
+        return Ok(());
+    };
+    run.stopped = Some(SystemTime::now());
+    run.exit = exit;
+    save(name, &runs)
+}
@@ -0,0 +1,127 @@
+use std::io;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::cgroup;
+use crate::logs::parse_size;
+
+/// Period of the CPU quota in `cpu.max`, in microseconds
+const CPU_PERIOD: u64 = 100_000;
+
+// How much of the machine a service may use
+#[derive(StructOpt, Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// Memory the service may use, e.g. 512M or 2G. Enforced with memory.max
+    /// in its cgroup, or as an address space limit without one
+    #[structopt(long = "memory-limit", parse(try_from_str = parse_size))]
+    pub memory: Option<u64>,
+    /// CPUs the service may use, e.g. 0.5 or 2. Only enforced in a cgroup
+    #[structopt(long = "cpu-quota", parse(try_from_str = parse_cpus))]
+    pub cpus: Option<f64>,
+    /// Maximum number of open files
+    #[structopt(long = "max-open-files")]
+    pub open_files: Option<u64>,
+    /// Maximum number of processes. Enforced with pids.max in its cgroup, or
+    /// for all processes of the user without one
+    #[structopt(long = "max-processes")]
+    pub processes: Option<u64>,
+    /// Largest core dump to write, e.g. 0 to disable them or 1G
+    #[structopt(long = "core-size", parse(try_from_str = parse_size))]
+    pub core_size: Option<u64>,
+}
+
+fn parse_cpus(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(cpus) if cpus > 0.0 && cpus.is_finite() => Ok(cpus),
+        _ => Err(format!("Invalid CPU quota {}, expected a positive number of CPUs", s)),
+    }
+}
+
+/// A limit set with `setrlimit` before the service is executed
+pub struct Rlimit {
+    pub resource: libc::__rlimit_resource_t,
+    pub name: &'static str,
+    pub value: u64,
+}
+
+impl ResourceLimits {
+    /// Whether some limits are best enforced by a cgroup
+    pub fn wants_cgroup(&self) -> bool {
+        self.memory.is_some() || self.cpus.is_some() || self.processes.is_some()
+    }
+
+    /// Writes the limits to the control files of the service's cgroup
+    pub fn apply_cgroup(&self, path: &Path) -> io::Result<()> {
+        let mut controllers = Vec::new();
+        if self.memory.is_some() {
+            controllers.push("memory");
+        }
+        if self.cpus.is_some() {
+            controllers.push("cpu");
+        }
+        if self.processes.is_some() {
+            controllers.push("pids");
+        }
+        if controllers.is_empty() {
+            return Ok(());
+        }
+        cgroup::enable_controllers(&controllers)?;
+        if let Some(memory) = self.memory {
+            cgroup::set(path, "memory.max", &memory.to_string())?;
+        }
+        if let Some(cpus) = self.cpus {
+            let quota = ((cpus * CPU_PERIOD as f64) as u64).max(1000);
+            cgroup::set(path, "cpu.max", &format!("{} {}", quota, CPU_PERIOD))?;
+        }
+        if let Some(processes) = self.processes {
+            cgroup::set(path, "pids.max", &processes.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// The rlimits to set, including stand-ins for the cgroup limits when
+    /// the service does not run in a cgroup that enforces them
+    pub fn rlimits(&self, in_cgroup: bool) -> Vec<Rlimit> {
+        let mut rlimits = Vec::new();
+        let mut add = |resource, name, value: Option<u64>| {
+            if let Some(value) = value {
+                rlimits.push(Rlimit { resource, name, value });
+            }
+        };
+        add(libc::RLIMIT_NOFILE, "max open files", self.open_files);
+        add(libc::RLIMIT_CORE, "core size", self.core_size);
+        if !in_cgroup {
+            add(libc::RLIMIT_AS, "memory limit", self.memory);
+            add(libc::RLIMIT_NPROC, "max processes", self.processes);
+        }
+        rlimits
+    }
+}
+
+/// The hard limit of the current process for `resource`
+pub fn hard_limit(resource: libc::__rlimit_resource_t) -> io::Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(resource, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit.rlim_max)
+}
+
+/// Applies the limits, only calls `setrlimit` so it is safe after fork
+pub fn set_rlimits(rlimits: &[Rlimit]) -> io::Result<()> {
+    for rlimit in rlimits {
+        let limit = libc::rlimit {
+            rlim_cur: rlimit.value,
+            rlim_max: rlimit.value,
+        };
+        if unsafe { libc::setrlimit(rlimit.resource, &limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,693 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::dirs::get_state_dir;
+use crate::time::{format_timestamp, parse_duration, parse_timestamp};
+
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How much of a log file is read at a time when searching it from the end
+const SEARCH_CHUNK: u64 = 64 * 1024;
+
+/// ANSI colors cycled through for the name prefixes of interleaved services
+const COLORS: [&str; 6] = ["36", "33", "32", "35", "34", "31"];
+
+/// Where the output of a service is written.
+/// Both paths are the same when the streams are merged.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogFiles {
+    pub stdout: PathBuf,
+    pub stderr: PathBuf,
+    #[serde(default)]
+    pub rotation: LogRotation,
+}
+
+// When log files are rotated and what happens to the rotated files
+#[derive(StructOpt, Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LogRotation {
+    /// Rotate a log file once it grows beyond this size, e.g. 500K, 10M or 1G
+    #[structopt(long = "log-max-size", parse(try_from_str = parse_size))]
+    pub max_size: Option<u64>,
+    /// Rotate a log file once it is older than this, e.g. 12h or 1d
+    #[structopt(long = "log-max-age", parse(try_from_str = parse_duration))]
+    pub max_age: Option<Duration>,
+    /// Number of rotated log files to keep
+    #[structopt(long = "log-keep", default_value = "5")]
+    pub keep: usize,
+    /// Compress rotated log files with gzip
+    #[structopt(long = "log-compress")]
+    pub compress: bool,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        LogRotation {
+            max_size: None,
+            max_age: None,
+            keep: 5,
+            compress: false,
+        }
+    }
+}
+
+impl LogRotation {
+    /// The command line flags that reproduce these settings
+    fn to_args(&self) -> Vec<String> {
+        let mut args = vec!["--log-keep".to_string(), self.keep.to_string()];
+        if let Some(max_size) = self.max_size {
+            args.extend(["--log-max-size".to_string(), max_size.to_string()]);
+        }
+        if let Some(max_age) = self.max_age {
+            args.extend(["--log-max-age".to_string(), max_age.as_secs().to_string()]);
+        }
+        if self.compress {
+            args.push("--log-compress".to_string());
+        }
+        args
+    }
+}
+
+/// Parses a size in bytes with an optional `K`, `M` or `G` suffix
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let upper = s.to_ascii_uppercase();
+    let trimmed = upper.trim_end_matches('B').trim_end_matches('I');
+    let (number, multiplier) = match trimmed.chars().last() {
+        Some('K') => (&trimmed[..trimmed.len() - 1], 1 << 10),
+        Some('M') => (&trimmed[..trimmed.len() - 1], 1 << 20),
+        Some('G') => (&trimmed[..trimmed.len() - 1], 1 << 30),
+        _ => (trimmed, 1),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("Invalid size {}", s))?;
+    number.checked_mul(multiplier).ok_or_else(|| format!("Size {} is too large", s))
+}
+
+impl LogFiles {
+    pub fn merged(&self) -> bool {
+        self.stdout == self.stderr
+    }
+
+    fn paths(&self) -> Vec<&Path> {
+        if self.merged() {
+            vec![&self.stdout]
+        } else {
+            vec![&self.stdout, &self.stderr]
+        }
+    }
+}
+
+fn get_log_dir() -> PathBuf {
+    let mut path = get_state_dir();
+    path.push("logs");
+    path
+}
+
+/// Picks the log paths for a service, `<name>.log` when merged and
+/// `<name>.out.log`/`<name>.err.log` otherwise
+pub fn log_files(name: &str, merged: bool, rotation: LogRotation) -> LogFiles {
+    let dir = get_log_dir();
+    if merged {
+        let path = dir.join(format!("{}.log", name));
+        LogFiles {
+            stdout: path.clone(),
+            stderr: path,
+            rotation,
+        }
+    } else {
+        LogFiles {
+            stdout: dir.join(format!("{}.out.log", name)),
+            stderr: dir.join(format!("{}.err.log", name)),
+            rotation,
+        }
+    }
+}
+
+/// The path of the `index`th rotated copy of a log file, e.g. `api.log.2.gz`
+fn rotated_path(path: &Path, index: usize, compressed: bool) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    if compressed {
+        name.push(".gz");
+    }
+    PathBuf::from(name)
+}
+
+/// Existing rotated copies of a log file, oldest first
+fn rotated_files(path: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for index in 1.. {
+        let plain = rotated_path(path, index, false);
+        let compressed = rotated_path(path, index, true);
+        if plain.exists() {
+            files.push(plain);
+        } else if compressed.exists() {
+            files.push(compressed);
+        } else {
+            break;
+        }
+    }
+    files.reverse();
+    files
+}
+
+/// A log file that rotates itself according to its settings while being written
+struct RotatingFile<'a> {
+    path: &'a Path,
+    rotation: &'a LogRotation,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+}
+
+impl<'a> RotatingFile<'a> {
+    fn open(path: &'a Path, rotation: &'a LogRotation) -> io::Result<RotatingFile<'a>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let opened = if metadata.len() == 0 {
+            SystemTime::now()
+        } else {
+            metadata.created().or_else(|_| metadata.modified())?
+        };
+        Ok(RotatingFile {
+            path,
+            rotation,
+            file,
+            size: metadata.len(),
+            opened,
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.rotation_due(line.len() as u64) {
+            self.rotate()?;
+        }
+        // A single write per line keeps lines intact when streams share a file
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotation_due(&self, incoming: u64) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_big = self.rotation.max_size.is_some_and(|max| self.size + incoming > max);
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max| self.opened.elapsed().unwrap_or_default() >= max);
+        too_big || too_old
+    }
+
+    /// Shifts the rotated copies up by one, moves the current file to `.1`
+    /// and starts a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.rotation.keep;
+        for compressed in [false, true] {
+            let oldest = rotated_path(self.path, keep.max(1), compressed);
+            if oldest.exists() {
+                fs::remove_file(oldest)?;
+            }
+            for index in (1..keep).rev() {
+                let from = rotated_path(self.path, index, compressed);
+                if from.exists() {
+                    fs::rename(from, rotated_path(self.path, index + 1, compressed))?;
+                }
+            }
+        }
+
+        if keep == 0 {
+            fs::remove_file(self.path)?;
+        } else {
+            let rotated = rotated_path(self.path, 1, false);
+            fs::rename(self.path, &rotated)?;
+            if self.rotation.compress {
+                compress_file(&rotated, &rotated_path(self.path, 1, true))?;
+            }
+        }
+
+        *self = RotatingFile::open(self.path, self.rotation)?;
+        Ok(())
+    }
+}
+
+/// Replaces `from` with a gzip compressed copy at `to`
+fn compress_file(from: &Path, to: &Path) -> io::Result<()> {
+    let mut input = File::open(from)?;
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(from)
+}
+
+/// Starts a detached `log-writer` process appending to `path` and returns
+/// the write end of its input pipe
+// The writer outlives us and exits on its own once the service closes the pipe
+#[allow(clippy::zombie_processes)]
+fn spawn_log_writer(path: &Path, rotation: &LogRotation) -> io::Result<OwnedFd> {
+    // Unlike `current_exe`, this still works after our binary was replaced by
+    // a rebuild while the supervisor keeps running
+    let mut writer = Command::new("/proc/self/exe")
+        .arg("log-writer")
+        .arg(path)
+        .args(rotation.to_args())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(writer.stdin.take().expect("Log writer has no stdin").into())
+}
+
+/// Sets up log writers for a service and returns its stdout and stderr
+pub fn open_log_files(logs: &LogFiles) -> io::Result<(Stdio, Stdio)> {
+    if let Some(dir) = logs.stdout.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let stdout = spawn_log_writer(&logs.stdout, &logs.rotation)?;
+    let stderr = if logs.merged() {
+        stdout.try_clone()?
+    } else {
+        spawn_log_writer(&logs.stderr, &logs.rotation)?
+    };
+    Ok((stdout.into(), stderr.into()))
+}
+
+/// Appends every line of `input` to `path`, prefixed with the time it was read.
+/// Owning the pipe lets the file be rotated without restarting the service.
+pub fn write_stamped(input: impl Read, path: &Path, rotation: &LogRotation) -> io::Result<()> {
+    let mut file = RotatingFile::open(path, rotation)?;
+    let mut input = BufReader::new(input);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+        let mut stamped = format_timestamp(SystemTime::now()).into_bytes();
+        stamped.push(b' ');
+        stamped.extend_from_slice(&line);
+        file.write_line(&stamped)?;
+    }
+}
+
+pub struct LogOptions {
+    pub follow: bool,
+    /// Number of lines to show per service before following
+    pub tail: Option<usize>,
+    pub since: Option<Duration>,
+    pub timestamps: bool,
+}
+
+struct LogLine {
+    /// `None` for lines written before output was timestamped
+    time: Option<SystemTime>,
+    text: String,
+}
+
+impl LogLine {
+    fn parse(raw: &str) -> LogLine {
+        if let Some((stamp, text)) = raw.split_once(' ') {
+            if let Some(time) = parse_timestamp(stamp) {
+                return LogLine {
+                    time: Some(time),
+                    text: text.to_string(),
+                };
+            }
+        }
+        LogLine {
+            time: None,
+            text: raw.to_string(),
+        }
+    }
+}
+
+/// Prints lines for one or more services
+struct Printer {
+    prefixes: HashMap<String, String>,
+    timestamps: bool,
+}
+
+impl Printer {
+    fn new(names: &[&str], timestamps: bool) -> Printer {
+        let color = env::var_os("NO_COLOR").is_none() && unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+        let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+        let prefixes = names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| {
+                let prefix = if names.len() == 1 {
+                    String::new()
+                } else if color {
+                    let code = COLORS[index % COLORS.len()];
+                    format!("\x1b[{}m{:<width$} |\x1b[0m ", code, name, width = width)
+                } else {
+                    format!("{:<width$} | ", name, width = width)
+                };
+                (name.to_string(), prefix)
+            })
+            .collect();
+        Printer { prefixes, timestamps }
+    }
+
+    fn print(&self, out: &mut impl Write, name: &str, line: &LogLine) -> io::Result<()> {
+        let prefix = &self.prefixes[name];
+        match line.time {
+            Some(time) if self.timestamps => {
+                writeln!(out, "{}{} {}", prefix, format_timestamp(time), line.text)
+            }
+            _ => writeln!(out, "{}{}", prefix, line.text),
+        }
+    }
+}
+
+/// Reads all lines of a rotated log file, decompressing it if needed
+fn read_rotated_lines(path: &Path) -> io::Result<Vec<LogLine>> {
+    let file = File::open(path)?;
+    let mut data = Vec::new();
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        GzDecoder::new(file).read_to_end(&mut data)?;
+    } else {
+        BufReader::new(file).read_to_end(&mut data)?;
+    }
+    Ok(String::from_utf8_lossy(&data).lines().map(LogLine::parse).collect())
+}
+
+/// Whether a line written to the current log files after `since` matches.
+/// The files are read from the end, only as far back as `since`.
+pub fn has_line_since(logs: &LogFiles, since: SystemTime, matches: impl Fn(&str) -> bool) -> io::Result<bool> {
+    'files: for path in logs.paths() {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let mut end = file.metadata()?.len();
+        // The start of a line whose beginning is in the chunk before
+        let mut partial = Vec::new();
+        while end > 0 {
+            let start = end.saturating_sub(SEARCH_CHUNK);
+            let mut chunk = vec![0; (end - start) as usize];
+            file.read_exact_at(&mut chunk, start)?;
+            chunk.extend_from_slice(&partial);
+            end = start;
+            let split = match chunk.iter().position(|b| *b == b'\n') {
+                _ if start == 0 => 0,
+                Some(newline) => newline + 1,
+                None => {
+                    partial = chunk;
+                    continue;
+                }
+            };
+            for raw in String::from_utf8_lossy(&chunk[split..]).lines().rev() {
+                let line = LogLine::parse(raw);
+                match line.time {
+                    Some(time) if time < since => continue 'files,
+                    Some(_) if matches(&line.text) => return Ok(true),
+                    _ => {}
+                }
+            }
+            partial = chunk[..split].to_vec();
+        }
+    }
+    Ok(false)
+}
+
+/// The value of a read, reporting the error instead of failing all of the
+/// logs for one unreadable file. A file that is gone was rotated away.
+fn or_report<T: Default>(path: &Path, result: io::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        if e.kind() != io::ErrorKind::NotFound {
+            eprintln!("Failed to read {}: {}", path.display(), e);
+        }
+        T::default()
+    })
+}
+
+/// Reads the complete lines of `path` starting at `offset`.
+/// Returns the lines and the offset after the last complete line.
+fn read_lines_from(path: &Path, offset: u64) -> io::Result<(Vec<LogLine>, u64)> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    let complete = data.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    let lines = String::from_utf8_lossy(&data[..complete])
+        .lines()
+        .map(LogLine::parse)
+        .collect();
+    Ok((lines, offset + complete as u64))
+}
+
+/// A log file being followed
+struct Followed<'a> {
+    name: &'a str,
+    path: &'a Path,
+    offset: u64,
+    /// Used to notice when the file is rotated
+    inode: u64,
+}
+
+impl Followed<'_> {
+    /// Starts over at the beginning when the file was replaced or truncated
+    fn check_rotated(&mut self) {
+        if let Ok(metadata) = fs::metadata(self.path) {
+            if metadata.ino() != self.inode || metadata.len() < self.offset {
+                self.inode = metadata.ino();
+                self.offset = 0;
+            }
+        }
+    }
+}
+
+/// Prints the logs of the given services, by name and log files
+pub fn show_logs(services: &[(String, Option<LogFiles>)], options: &LogOptions) -> io::Result<()> {
+    let names: Vec<&str> = services.iter().map(|(name, _)| name.as_str()).collect();
+    let printer = Printer::new(&names, options.timestamps);
+    let since = options.since.map(|since| SystemTime::now() - since);
+
+    let mut history = Vec::new();
+    let mut followed = Vec::new();
+    for (name, logs) in services {
+        let Some(logs) = logs else {
+            eprintln!("Service {} has no captured logs", name);
+            continue;
+        };
+
+        let mut lines = Vec::new();
+        for path in logs.paths() {
+            for rotated in rotated_files(path) {
+                lines.extend(or_report(&rotated, read_rotated_lines(&rotated)));
+            }
+            let inode = fs::metadata(path).map(|m| m.ino()).unwrap_or(0);
+            let (file_lines, offset) = or_report(path, read_lines_from(path, 0));
+            lines.extend(file_lines);
+            followed.push(Followed {
+                name,
+                path,
+                offset,
+                inode,
+            });
+        }
+        // Lines from separate stdout and stderr files are merged by time
+        lines.sort_by_key(|line| line.time.unwrap_or(UNIX_EPOCH));
+        if let Some(since) = since {
+            lines.retain(|line| line.time.is_some_and(|time| time >= since));
+        }
+        if let Some(tail) = options.tail {
+            lines.drain(..lines.len().saturating_sub(tail));
+        }
+        history.extend(lines.into_iter().map(|line| (name.as_str(), line)));
+    }
+
+    history.sort_by_key(|(_, line)| line.time.unwrap_or(UNIX_EPOCH));
+    let mut out = io::stdout().lock();
+    for (name, line) in &history {
+        printer.print(&mut out, name, line)?;
+    }
+
+    if !options.follow {
+        return Ok(());
+    }
+    loop {
+        for file in &mut followed {
+            file.check_rotated();
+            // A failed read is tried again from the same offset
+            let read = read_lines_from(file.path, file.offset).map(Some);
+            let Some((lines, offset)) = or_report(file.path, read) else {
+                continue;
+            };
+            file.offset = offset;
+            for line in &lines {
+                printer.print(&mut out, file.name, line)?;
+            }
+        }
+        out.flush()?;
+        thread::sleep(FOLLOW_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::truncate_to_millis;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cargo-service-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn merged_logs(path: &Path) -> LogFiles {
+        LogFiles {
+            stdout: path.to_path_buf(),
+            stderr: path.to_path_buf(),
+            rotation: LogRotation::default(),
+        }
+    }
+
+    #[test]
+    fn lines_are_found_across_chunk_boundaries() {
+        let dir = temp_dir("chunks");
+        let path = dir.join("api.log");
+        let since = truncate_to_millis(SystemTime::now());
+        let stamp = format_timestamp(since);
+        // The first chunk read from the end starts in the middle of the
+        // matching line
+        let wanted = format!("{} listening on port 8080\n", stamp);
+        let boundary = stamp.len() + 15;
+        let filler_len = SEARCH_CHUNK as usize - (wanted.len() - boundary);
+        let filler = format!("{} {}\n", stamp, "x".repeat(filler_len - stamp.len() - 2));
+        fs::write(&path, format!("{}{}", wanted, filler)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len() - SEARCH_CHUNK, boundary as u64);
+
+        let logs = merged_logs(&path);
+        assert!(has_line_since(&logs, since, |text| text == "listening on port 8080").unwrap());
+        assert!(!has_line_since(&logs, since, |text| text == "stopped").unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lines_without_a_newline_are_found() {
+        let dir = temp_dir("unterminated");
+        let path = dir.join("api.log");
+        let since = truncate_to_millis(SystemTime::now());
+        let stamp = format_timestamp(since);
+        fs::write(&path, format!("{} starting\n{} ready", stamp, stamp)).unwrap();
+        assert!(has_line_since(&merged_logs(&path), since, |text| text == "ready").unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lines_before_since_do_not_count() {
+        let dir = temp_dir("since");
+        let path = dir.join("api.log");
+        let since = truncate_to_millis(SystemTime::now());
+        let before = format_timestamp(since - Duration::from_secs(1));
+        let after = format_timestamp(since);
+        fs::write(&path, format!("{} ready\n{} ready\n{} started\n", after, before, after)).unwrap();
+        // The search stops at the older line, whatever was written before it
+        assert!(!has_line_since(&merged_logs(&path), since, |text| text == "ready").unwrap());
+        assert!(has_line_since(&merged_logs(&path), since, |text| text == "started").unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Writes `lines` with a rotation after each and returns what the rotated
+    /// copies hold, newest first
+    fn rotate_lines(name: &str, rotation: LogRotation, lines: &[&str]) -> (PathBuf, Vec<String>) {
+        let dir = temp_dir(name);
+        let path = dir.join("api.log");
+        let mut file = RotatingFile::open(&path, &rotation).unwrap();
+        for line in lines {
+            file.write_line(format!("{}\n", line).as_bytes()).unwrap();
+            file.rotate().unwrap();
+        }
+        let mut contents = Vec::new();
+        for index in 1..=lines.len() {
+            let plain = rotated_path(&path, index, false);
+            let compressed = rotated_path(&path, index, true);
+            let mut text = String::new();
+            if compressed.exists() {
+                GzDecoder::new(File::open(compressed).unwrap()).read_to_string(&mut text).unwrap();
+            } else if plain.exists() {
+                text = fs::read_to_string(plain).unwrap();
+            } else {
+                continue;
+            }
+            contents.push(text);
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        (dir, contents)
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest_copies() {
+        let rotation = LogRotation {
+            keep: 2,
+            ..LogRotation::default()
+        };
+        let (dir, contents) = rotate_lines("rotate", rotation, &["one", "two", "three"]);
+        assert_eq!(contents, ["three\n", "two\n"]);
+        assert!(!rotated_path(&dir.join("api.log"), 3, false).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_without_copies_starts_over() {
+        let rotation = LogRotation {
+            keep: 0,
+            ..LogRotation::default()
+        };
+        let (dir, contents) = rotate_lines("rotate-none", rotation, &["one", "two"]);
+        assert!(contents.is_empty());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_copies_can_be_compressed() {
+        let rotation = LogRotation {
+            keep: 2,
+            compress: true,
+            ..LogRotation::default()
+        };
+        let (dir, contents) = rotate_lines("rotate-gzip", rotation, &["one", "two", "three"]);
+        assert_eq!(contents, ["three\n", "two\n"]);
+        let path = dir.join("api.log");
+        for index in 1..=3 {
+            assert!(!rotated_path(&path, index, false).exists(), "{}", index);
+        }
+        assert!(!rotated_path(&path, 3, true).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sizes_take_a_suffix() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("10K"), Ok(10 << 10));
+        assert_eq!(parse_size("10m"), Ok(10 << 20));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size("1kb"), Ok(1 << 10));
+        for s in ["", "M", "1T", "-1", "1.5G", "99999999999G"] {
+            assert!(parse_size(s).is_err(), "{}", s);
+        }
+    }
+}
@@ -1,129 +1,1204 @@
+use std::collections::BTreeMap;
 use std::env;
-use std::fs::{self, File};
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::str::FromStr;
-use ron::de::from_reader;
-use ron::ser::{to_string_pretty, PrettyConfig};
-use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use structopt::StructOpt;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Service {
-    binary_path: String,
-    pid: Option<u32>,
-}
+mod cargo;
+mod cgroup;
+mod dirs;
+mod error;
+mod graph;
+mod health;
+mod history;
+mod limits;
+mod logs;
+mod manifest;
+mod output;
+mod process;
+mod protocol;
+mod service;
+mod supervisor;
+mod time;
+mod user;
+mod watch;
+
+use cargo::{CargoArgs, CargoTarget};
+use error::{Error, Result};
+use health::{HealthChecks, HealthState};
+use history::Run;
+use limits::ResourceLimits;
+use logs::{LogFiles, LogOptions, LogRotation};
+use output::{Format, Record, Records};
+use process::{ProcessInfo, StopPath};
+use protocol::{ExitInfo, Request, Response};
+use service::{
+    current_dir, default_name, parse_env_var, parse_name, resolve_binary_path, resolve_path, Service, ServiceState,
+};
+use serde::{Serialize, Serializer};
+use supervisor::RestartSettings;
 
 #[derive(StructOpt)]
+#[structopt(after_help = error::EXIT_CODES)]
 struct Cli {
+    /// Manage the system-wide services kept in /var/lib/cargo-service instead of your own
+    #[structopt(long, global = true)]
+    system: bool,
+    /// Keep the registry, logs and supervisor files in this directory, overrides CARGO_SERVICE_HOME
+    #[structopt(long, global = true, parse(from_os_str), conflicts_with = "system")]
+    state_dir: Option<PathBuf>,
+    /// How start, stop, restart, reload, list, status, history, up and down print their results:
+    /// table, json or ron. JSON and RON records carry a schema_version.
+    #[structopt(long, global = true, default_value = "table")]
+    format: Format,
     #[structopt(subcommand)]
     action: Action,
 }
 
+// Only ever parsed once, so the size of the start options does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt)]
 enum Action {
+    // The option structs flattened into subcommands have no doc comments, as
+    // structopt would show them instead of the subcommand's own description
+    /// Start a binary, or the binaries of a Cargo project, as supervised services
     Start {
         /// The path to the binary to run as a service
-        binary_path: Service,
+        #[structopt(
+            required_unless_one = &["bin", "workspace"],
+            conflicts_with_all = &["bin", "workspace", "package", "release", "profile", "features", "manifest_path"]
+        )]
+        binary_path: Option<String>,
+        #[structopt(flatten)]
+        cargo: CargoArgs,
+        /// The name of the service, defaults to the binary file name
+        #[structopt(long, parse(try_from_str = parse_name))]
+        name: Option<String>,
+        /// Environment variable to set, as KEY=VALUE (can be repeated)
+        #[structopt(long = "env", number_of_values = 1, parse(try_from_str = parse_env_var))]
+        env: Vec<(String, String)>,
+        /// File with KEY=VALUE lines to load into the environment
+        #[structopt(long, parse(from_os_str))]
+        env_file: Option<PathBuf>,
+        /// The working directory of the service
+        #[structopt(long, parse(from_os_str))]
+        cwd: Option<PathBuf>,
+        /// Arguments passed to the binary, given after `--`
+        #[structopt(last = true)]
+        args: Vec<String>,
+        /// Write stdout and stderr to a single log file instead of two
+        #[structopt(long)]
+        merge_logs: bool,
+        #[structopt(flatten)]
+        log_rotation: LogRotation,
+        #[structopt(flatten)]
+        restart: RestartSettings,
+        /// The signal `reload` sends to make the service reread its configuration
+        #[structopt(long, default_value = "HUP", parse(try_from_str = process::parse_signal))]
+        reload_signal: libc::c_int,
+        /// A service that has to be running first (can be repeated)
+        #[structopt(long, number_of_values = 1)]
+        depends_on: Vec<String>,
+        #[structopt(flatten)]
+        checks: HealthChecks,
+        /// Wait until the services pass their readiness checks before returning
+        #[structopt(long)]
+        wait: bool,
+        /// Run the service in its own cgroup v2 subtree, so that no descendant escapes when it is stopped
+        #[structopt(long)]
+        cgroup: bool,
+        #[structopt(flatten)]
+        limits: ResourceLimits,
+        /// Run the service as this user, by name or id. Needs root.
+        #[structopt(long)]
+        user: Option<String>,
+        /// Run the service with this group, defaults to the primary group of --user
+        #[structopt(long)]
+        group: Option<String>,
+        /// Supplementary group (can be repeated), defaults to the groups of --user
+        #[structopt(long = "groups", number_of_values = 1)]
+        groups: Vec<String>,
     },
+    /// Stop a service, and before it every service that depends on it
     Stop {
         /// The name of the service to stop
-        service_name: Service,
+        name: String,
+        /// The signal sent first to ask the service to exit
+        #[structopt(long, default_value = "TERM", parse(try_from_str = process::parse_signal))]
+        signal: libc::c_int,
+        /// Seconds to wait for the service to exit before sending SIGKILL
+        #[structopt(long, default_value = "10")]
+        timeout: u64,
+    },
+    /// Stop a service and start it again with the same arguments, environment and directory
+    Restart {
+        /// The name of the service to restart
+        name: String,
+        /// The signal sent first to ask the service to exit
+        #[structopt(long, default_value = "TERM", parse(try_from_str = process::parse_signal))]
+        signal: libc::c_int,
+        /// Seconds to wait for the service to exit before sending SIGKILL
+        #[structopt(long, default_value = "10")]
+        timeout: u64,
+        /// Rebuild a Cargo service first, it keeps running if the build fails
+        #[structopt(long)]
+        build: bool,
+    },
+    /// Send a service its reload signal so it rereads its configuration in place
+    Reload {
+        /// The name of the service
+        name: String,
+    },
+    /// List all tracked services
+    List,
+    /// Show details about a service
+    Status {
+        /// The name of the service
+        name: String,
+    },
+    /// Show when the processes of a service started and how they exited
+    History {
+        /// The name of the service
+        #[structopt(parse(try_from_str = parse_name))]
+        name: String,
+        /// Only show the last N runs
+        #[structopt(short = "n", long)]
+        limit: Option<usize>,
+    },
+    /// Show the captured output of one or more services
+    Logs {
+        /// The names of the services, their output is interleaved
+        #[structopt(required = true)]
+        names: Vec<String>,
+        /// Keep printing new output as it is written
+        #[structopt(short, long)]
+        follow: bool,
+        /// Number of lines to show from the end of the logs of each service
+        #[structopt(short = "n", long)]
+        tail: Option<usize>,
+        /// Only show lines newer than this, e.g. 30s, 10m or 2h
+        #[structopt(long, parse(try_from_str = time::parse_duration))]
+        since: Option<Duration>,
+        /// Show the time each line was written
+        #[structopt(short, long)]
+        timestamps: bool,
+    },
+    /// Start every service declared in Services.ron or Cargo.toml
+    Up {
+        /// The manifest to use instead of looking for one
+        #[structopt(long, parse(from_os_str))]
+        manifest: Option<PathBuf>,
+    },
+    /// Stop every service declared in Services.ron or Cargo.toml
+    Down {
+        /// The manifest to use instead of looking for one
+        #[structopt(long, parse(from_os_str))]
+        manifest: Option<PathBuf>,
+        /// Seconds to wait for each service to exit before sending SIGKILL
+        #[structopt(long, default_value = "10")]
+        timeout: u64,
+    },
+    /// Print the dependency graph of the services in Graphviz DOT
+    Graph {
+        /// Use the services declared in Services.ron or Cargo.toml instead of the running ones
+        #[structopt(long)]
+        declared: bool,
+        /// The manifest to use instead of looking for one, implies --declared
+        #[structopt(long, parse(from_os_str))]
+        manifest: Option<PathBuf>,
+    },
+    /// Rebuild and restart a Cargo service whenever its sources change
+    Watch {
+        /// The name of the service
+        name: String,
+    },
+    /// Print what happens to services as it happens
+    Events,
+    /// Runs the supervisor that owns and restarts the services
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    Supervise,
+    /// Appends stdin to a log file with timestamps, used for service output
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    LogWriter {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(flatten)]
+        rotation: LogRotation,
     },
 }
 
-impl FromStr for Service {
-    type Err = String;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Service {
-            binary_path: s.to_string(),
-            pid: None,
-        })
+impl Action {
+    /// Whether the command prints records that `--format` applies to
+    fn prints_records(&self) -> bool {
+        matches!(
+            self,
+            Action::Start { .. }
+                | Action::Stop { .. }
+                | Action::Restart { .. }
+                | Action::Reload { .. }
+                | Action::List
+                | Action::Status { .. }
+                | Action::History { .. }
+                | Action::Up { .. }
+                | Action::Down { .. }
+        )
     }
-}
 
-impl Action {
-    fn run(self) {
+    fn run(self, format: Format) -> Result<()> {
+        if format != Format::Table && !self.prints_records() {
+            return Err(Error::Invalid(
+                "This command only prints text, --format json and ron are not supported".to_string(),
+            ));
+        }
         match self {
-            Action::Start { binary_path } => start_service(binary_path),
-            Action::Stop { service_name } => stop_service(service_name),
+            Action::Start {
+                binary_path,
+                cargo,
+                name,
+                env,
+                env_file,
+                cwd,
+                args,
+                merge_logs,
+                log_rotation,
+                restart,
+                reload_signal,
+                depends_on,
+                checks,
+                wait,
+                cgroup,
+                limits,
+                user,
+                group,
+                groups,
+            } => {
+                let binaries = match binary_path {
+                    Some(binary_path) => vec![(resolve_binary_path(&current_dir()?, binary_path), None)],
+                    None => build_cargo_binaries(&cargo)?,
+                };
+                let env: BTreeMap<String, String> = env.into_iter().collect();
+                let env_file = env_file.map(absolute_path).transpose()?;
+                // Without --cwd the service runs where it was started from, not where the supervisor was
+                let cwd = match cwd {
+                    Some(cwd) => absolute_path(cwd)?,
+                    None => current_dir()?,
+                };
+
+                let mut names = Vec::new();
+                let mut started = Records::new(format);
+                let mut errors = Vec::new();
+                for (binary_path, target) in binaries {
+                    let name = match (&name, &target) {
+                        (Some(name), _) => name.clone(),
+                        (None, Some(target)) => target.bin.clone(),
+                        (None, None) => default_name(&binary_path),
+                    };
+                    let mut service = Service::new(name, binary_path);
+                    service.cargo = target;
+                    service.logs = Some(logs::log_files(&service.name, merge_logs, log_rotation.clone()));
+                    service.args = args.clone();
+                    service.env = env.clone();
+                    service.env_file = env_file.clone();
+                    service.cwd = Some(cwd.clone());
+                    service.restart = restart.clone();
+                    service.reload_signal = reload_signal;
+                    service.depends_on = depends_on.clone();
+                    service.checks = checks.clone();
+                    service.cgroup = cgroup;
+                    service.limits = limits.clone();
+                    service.user = user.clone();
+                    service.group = group.clone();
+                    service.groups = groups.clone();
+                    names.push(service.name.clone());
+                    match start_service(service) {
+                        Ok(record) => started.push(record),
+                        Err(e) => errors.push(e),
+                    }
+                }
+                started.finish()?;
+                error::collect(errors)?;
+                if wait {
+                    let errors = names.iter().filter_map(|name| wait_ready(name, format).err()).collect();
+                    error::collect(errors)?;
+                }
+                if cargo.watch {
+                    watch_service(&names[0])?;
+                }
+                Ok(())
+            }
+            Action::Stop { name, signal, timeout } => {
+                stop_with_dependents(&name, signal, Duration::from_secs(timeout), format)
+            }
+            Action::Restart { name, signal, timeout, build } => {
+                restart_service(&name, signal, Duration::from_secs(timeout), build, format)
+            }
+            Action::Reload { name } => reload_service(&name, format),
+            Action::List => list_services(format),
+            Action::Status { name } => service_status(&name, format),
+            Action::History { name, limit } => service_history(&name, limit, format),
+            Action::Logs { names, follow, tail, since, timestamps } => {
+                let options = LogOptions {
+                    follow,
+                    tail,
+                    since,
+                    timestamps,
+                };
+                show_logs(&names, &options)
+            }
+            Action::Up { manifest } => up(manifest.as_deref(), format),
+            Action::Down { manifest, timeout } => {
+                down(manifest.as_deref(), Duration::from_secs(timeout), format)
+            }
+            Action::Graph { declared, manifest } => {
+                let services = if declared || manifest.is_some() {
+                    manifest::load_manifest(manifest.as_deref())?.to_services()
+                } else {
+                    fetch_services(None)?
+                };
+                print!("{}", graph::to_dot(&graph::nodes(&services)));
+                Ok(())
+            }
+            Action::Watch { name } => watch_service(&name),
+            Action::Events => show_events(),
+            Action::Supervise => supervisor::run(),
+            Action::LogWriter { path, rotation } => logs::write_stamped(io::stdin(), &path, &rotation)
+                .map_err(|e| Error::io(format!("Failed to write {}", path.display()), e)),
         }
     }
 }
 
 fn main() {
-    let args = Cli::from_args();
-    args.action.run();
+    // Cargo runs `cargo service <args>` as `cargo-service service <args>`
+    let mut args: Vec<_> = env::args_os().collect();
+    if args.get(1).is_some_and(|arg| arg == "service") {
+        args.remove(1);
+    }
+    let cli = Cli::from_iter_safe(args).unwrap_or_else(|e| {
+        // --help and --version are not errors
+        if !e.use_stderr() {
+            e.exit();
+        }
+        eprintln!("{}", e.message);
+        std::process::exit(Error::Invalid(String::new()).exit_code());
+    });
+    // Log writers only write the file they are given. They are started without
+    // the supervisor's directory flags and would set up the default ones.
+    let init = match cli.action {
+        Action::LogWriter { .. } => Ok(()),
+        _ => dirs::init(cli.state_dir, cli.system),
+    };
+    if let Err(e) = init.and_then(|_| cli.action.run(cli.format)) {
+        eprintln!("{}", e);
+        std::process::exit(e.exit_code());
+    }
 }
 
-fn start_service(binary_path: Service) {
-    let mut services = load_services();
+/// Resolves a path against the current directory so that it still points to
+/// the same place when the service is restarted from elsewhere
+fn absolute_path(path: PathBuf) -> Result<PathBuf> {
+    Ok(resolve_path(&current_dir()?, &path))
+}
 
-    if services.iter().any(|s| s.binary_path == binary_path.binary_path) {
-        eprintln!("Service with binary path {} already exists", binary_path.binary_path);
+/// Resolves and builds the binaries selected with `--bin` or `--workspace`
+/// in one `cargo build`, returning their paths
+fn build_cargo_binaries(args: &CargoArgs) -> Result<Vec<(String, Option<CargoTarget>)>> {
+    let manifest_path = args.manifest_path.clone().map(absolute_path).transpose()?;
+    let metadata = cargo::metadata(manifest_path.as_deref()).map_err(Error::Other)?;
+    let targets = if args.workspace {
+        let targets = args.resolve_workspace(&metadata).map_err(Error::Invalid)?;
+        if targets.is_empty() {
+            return Err(Error::Invalid("The workspace has no binary targets".to_string()));
+        }
+        targets
     } else {
-        let child = Command::new(&binary_path.binary_path)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-            .expect("Failed to start service");
+        let bin = args.bin.as_deref().expect("Either a binary path or --bin is required");
+        vec![args.resolve(&metadata, bin).map_err(Error::Invalid)?]
+    };
+    cargo::build(&targets).map_err(Error::Other)?;
+
+    Ok(targets
+        .into_iter()
+        .map(|target| {
+            let binary_path = cargo::binary_path(&metadata, &target);
+            (binary_path.to_string_lossy().into_owned(), Some(target))
+        })
+        .collect())
+}
+
+#[derive(Serialize)]
+struct Started {
+    name: String,
+    pid: u32,
+}
+
+impl Record for Started {
+    fn print_table(&self) {
+        println!("Service {} started with pid {}", self.name, self.pid);
+    }
+}
+
+struct Stopped {
+    name: String,
+    path: StopPath,
+    exit: Option<ExitInfo>,
+    timeout: Duration,
+}
+
+impl Serialize for Stopped {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let (how, signal) = match self.path {
+            StopPath::NotRunning => ("not-running", None),
+            StopPath::Graceful(signal) => ("signal", Some(process::signal_name(signal))),
+            StopPath::Killed => ("killed", Some(process::signal_name(libc::SIGKILL))),
+        };
+        StoppedDocument {
+            name: &self.name,
+            how,
+            signal,
+            exit: self.exit.as_ref().map(ExitDocument::new),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl Record for Stopped {
+    fn print_table(&self) {
+        let name = &self.name;
+        match self.path {
+            StopPath::NotRunning => println!("Service {} was not running", name),
+            StopPath::Graceful(signal) => {
+                println!("Service {} stopped with {}", name, process::signal_name(signal))
+            }
+            StopPath::Killed => println!(
+                "Service {} did not exit within {}s and was killed with SIGKILL",
+                name,
+                self.timeout.as_secs()
+            ),
+        }
+        if let Some(exit) = &self.exit {
+            println!("Exit status: {}", exit);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Restarted {
+    stopped: Stopped,
+    pid: u32,
+}
 
-        let service_name = binary_path.binary_path.clone();
-        let mut service = binary_path;
-        service.pid = Some(child.id());
-        services.push(service);
-        save_services(&services);
-        println!("Service with binary path {} started", service_name);
+impl Record for Restarted {
+    fn print_table(&self) {
+        self.stopped.print_table();
+        println!("Service {} restarted with pid {}", self.stopped.name, self.pid);
     }
 }
 
-fn stop_service(service_name: Service) {
-    let mut services = load_services();
+#[derive(Serialize)]
+struct Reloaded {
+    name: String,
+    pid: u32,
+    signal: String,
+}
+
+impl Record for Reloaded {
+    fn print_table(&self) {
+        println!("Sent {} to service {} (pid {})", self.signal, self.name, self.pid);
+    }
+}
 
-    if let Some(index) = services.iter().position(|s| s.binary_path == service_name.binary_path) {
-        let service = &services[index];
-        let pid = service.pid.expect("Service PID not found");
+/// Asks the supervisor to start a service
+fn start_service(service: Service) -> Result<Started> {
+    let name = service.name.clone();
+    // Variables that are not valid UTF-8 cannot be sent to the supervisor
+    let env = env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+        .collect();
+    match protocol::send(Request::Start { service: Box::new(service), env })? {
+        Response::Started { pid } => Ok(Started { name, pid }),
+        other => Err(protocol::unexpected(other)),
+    }
+}
 
-        Command::new("kill")
-            .arg("-9")
-            .arg(pid.to_string())
-            .output()
-            .expect("Failed to stop service");
+/// Waits until the supervisor reports the service as ready
+fn wait_ready(name: &str, format: Format) -> Result<()> {
+    match protocol::send(Request::WaitReady { name: name.to_string() })? {
+        Response::Ready => {
+            if format == Format::Table {
+                println!("Service {} is ready", name);
+            }
+            Ok(())
+        }
+        other => Err(protocol::unexpected(other)),
+    }
+}
 
-        services.remove(index);
-        save_services(&services);
-        println!("Service with binary path {} stopped", service_name.binary_path);
+fn stop_service(name: &str, signal: libc::c_int, timeout: Duration) -> Result<Stopped> {
+    let request = Request::Stop {
+        name: name.to_string(),
+        signal,
+        timeout,
+    };
+    match protocol::send(request)? {
+        Response::Stopped { path, exit } => Ok(Stopped {
+            name: name.to_string(),
+            path,
+            exit,
+            timeout,
+        }),
+        other => Err(protocol::unexpected(other)),
+    }
+}
+
+fn restart_service(
+    name: &str,
+    signal: libc::c_int,
+    timeout: Duration,
+    build: bool,
+    format: Format,
+) -> Result<()> {
+    if build {
+        let services = fetch_services(Some(name))?;
+        let Some(target) = &services[0].cargo else {
+            return Err(Error::Invalid(format!(
+                "Service {} was not started from a Cargo binary",
+                name
+            )));
+        };
+        cargo::build(std::slice::from_ref(target)).map_err(Error::Other)?;
+    }
+    let request = Request::Restart {
+        name: name.to_string(),
+        signal,
+        timeout,
+    };
+    match protocol::send(request)? {
+        Response::Restarted { path, exit, pid } => {
+            let stopped = Stopped {
+                name: name.to_string(),
+                path,
+                exit,
+                timeout,
+            };
+            output::print(format, &Restarted { stopped, pid })
+        }
+        other => Err(protocol::unexpected(other)),
+    }
+}
+
+fn reload_service(name: &str, format: Format) -> Result<()> {
+    match protocol::send(Request::Reload { name: name.to_string() })? {
+        Response::Reloaded { pid, signal } => output::print(
+            format,
+            &Reloaded {
+                name: name.to_string(),
+                pid,
+                signal: process::signal_name(signal),
+            },
+        ),
+        other => Err(protocol::unexpected(other)),
+    }
+}
+
+/// Stops the services that depend on `name`, dependents first, and then `name` itself
+fn stop_with_dependents(name: &str, signal: libc::c_int, timeout: Duration, format: Format) -> Result<()> {
+    let services = fetch_services(None)?;
+    if !services.iter().any(|service| service.name == name) {
+        return Err(Error::NotFound(name.to_string()));
+    }
+    let nodes = graph::nodes(&services);
+    let affected = graph::with_dependents(name, &nodes);
+    let selected: graph::Nodes = nodes.iter().copied().filter(|(n, _)| affected.contains(n)).collect();
+    let order = graph::topological_order(&selected).map_err(Error::Invalid)?;
+    let mut stopped = Records::new(format);
+    let result = order
+        .into_iter()
+        .rev()
+        .try_for_each(|service| stop_service(service, signal, timeout).map(|record| stopped.push(record)));
+    stopped.finish()?;
+    result
+}
+
+/// Fetches all services, or only the named one, from the supervisor
+fn fetch_services(name: Option<&str>) -> Result<Vec<Service>> {
+    let request = Request::Status {
+        name: name.map(str::to_string),
+    };
+    match protocol::send(request)? {
+        Response::Services(services) => Ok(services),
+        other => Err(protocol::unexpected(other)),
+    }
+}
+
+/// Formats a duration using its two most significant units, e.g. `3h 12m`
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, seconds) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
     } else {
-        panic!("Service with binary path {} not found", service_name.binary_path);
+        format!("{}s", seconds)
     }
 }
 
-fn load_services() -> Vec<Service> {
-    let path = get_config_path();
-    if path.exists() {
-        let file = File::open(&path).expect("Failed to open file");
-        from_reader(file).expect("Failed to read file")
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
     } else {
-        Vec::new()
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// The limits that are set, empty when there are none
+fn format_limits(limits: &ResourceLimits) -> String {
+    let mut parts = Vec::new();
+    if let Some(memory) = limits.memory {
+        parts.push(format!("memory {}", format_bytes(memory)));
+    }
+    if let Some(cpus) = limits.cpus {
+        parts.push(format!("{} CPUs", cpus));
+    }
+    if let Some(open_files) = limits.open_files {
+        parts.push(format!("{} open files", open_files));
+    }
+    if let Some(processes) = limits.processes {
+        parts.push(format!("{} processes", processes));
+    }
+    if let Some(core_size) = limits.core_size {
+        parts.push(format!("core size {}", format_bytes(core_size)));
+    }
+    parts.join(", ")
+}
+
+/// Describes the state of a service, checking that a running one is still alive
+fn state_label(service: &Service, info: Option<&ProcessInfo>) -> &'static str {
+    match (service.state, service.pid, info) {
+        (ServiceState::Running, Some(_), None) => "dead",
+        (state, _, _) => state.label(),
     }
 }
 
-fn save_services(services: &[Service]) {
-    let path = get_config_path();
-    let pretty = PrettyConfig::new();
-    let data = to_string_pretty(services, pretty).expect("Failed to serialize data");
-    let mut file = File::create(&path).expect("Failed to create file");
-    file.write_all(data.as_bytes()).expect("Failed to write file");
+/// A service with what is known about its process right now
+struct ServiceRecord {
+    service: Service,
+    state: &'static str,
+    process: Option<ProcessInfo>,
+    /// Other processes the service started, as pid and command line
+    descendants: Vec<(u32, String)>,
 }
 
-#[allow(deprecated)]
-fn get_config_path() -> PathBuf {
-    let mut path = env::home_dir().expect("Failed to get home directory");
-    path.push(".config");
-    path.push("cargo-service");
-    fs::create_dir_all(&path).expect("Failed to create directory");
-    path.push("cache.ron");
-    path
-}
\ No newline at end of file
+impl ServiceRecord {
+    fn new(service: Service) -> Self {
+        let process = service.running_pid().and_then(process::inspect);
+        ServiceRecord {
+            state: state_label(&service, process.as_ref()),
+            descendants: service.descendants(),
+            service,
+            process,
+        }
+    }
+}
+
+impl Serialize for ServiceRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let ServiceRecord { service, state, process, descendants } = self;
+        let now = SystemTime::now();
+        ServiceDocument {
+            name: &service.name,
+            state,
+            pid: service.pid,
+            binary: &service.binary_path,
+            args: &service.args,
+            env: &service.env,
+            env_file: service.env_file.as_deref(),
+            cwd: service.cwd.as_deref(),
+            user: service.user.as_deref(),
+            group: service.group.as_deref(),
+            groups: &service.groups,
+            restart: RestartDocument {
+                policy: service.restart.policy.label(),
+                max_restarts: service.restart.max_restarts,
+                restart_window_secs: service.restart.restart_window.as_secs(),
+            },
+            restarts: service.restarts,
+            reload_signal: process::signal_name(service.reload_signal),
+            depends_on: &service.depends_on,
+            health: service.checks.any().then(|| HealthDocument {
+                state: service.health.state.label(),
+                readiness: service.checks.readiness.as_ref().map(ToString::to_string),
+                liveness: service.checks.liveness.as_ref().map(ToString::to_string),
+                failures: service.health.failures,
+                last_error: service.health.last_error.as_deref(),
+            }),
+            cgroup: service.cgroup_path.as_deref(),
+            limits: &service.limits,
+            cargo: service.cargo.as_ref(),
+            logs: service.logs.as_ref().map(|logs| LogsDocument {
+                stdout: &logs.stdout,
+                stderr: &logs.stderr,
+            }),
+            process: process.as_ref().map(|info| ProcessDocument {
+                started: now.checked_sub(info.uptime).map(time::format_timestamp),
+                uptime_secs: info.uptime.as_secs(),
+                command: &info.cmdline,
+                cwd: info.cwd.as_deref(),
+                rss_bytes: info.rss_bytes,
+                cpu_percent: info.cpu_percent,
+            }),
+            descendants: descendants
+                .iter()
+                .map(|(pid, command)| DescendantDocument { pid: *pid, command })
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+// What `--format json` and `--format ron` print, apart from the registry so
+// that its internals can change without breaking scripts. Times are RFC 3339
+// in UTC, durations whole seconds and enums lowercase labels.
+
+#[derive(Serialize)]
+struct ServiceDocument<'a> {
+    name: &'a str,
+    state: &'a str,
+    pid: Option<u32>,
+    binary: &'a str,
+    args: &'a [String],
+    env: &'a BTreeMap<String, String>,
+    env_file: Option<&'a Path>,
+    cwd: Option<&'a Path>,
+    user: Option<&'a str>,
+    group: Option<&'a str>,
+    groups: &'a [String],
+    restart: RestartDocument,
+    restarts: u32,
+    reload_signal: String,
+    depends_on: &'a [String],
+    /// Only for services with checks
+    health: Option<HealthDocument<'a>>,
+    cgroup: Option<&'a Path>,
+    limits: &'a ResourceLimits,
+    cargo: Option<&'a CargoTarget>,
+    logs: Option<LogsDocument<'a>>,
+    /// Only while the process is alive
+    process: Option<ProcessDocument<'a>>,
+    descendants: Vec<DescendantDocument<'a>>,
+}
+
+#[derive(Serialize)]
+struct RestartDocument {
+    policy: &'static str,
+    max_restarts: usize,
+    restart_window_secs: u64,
+}
+
+#[derive(Serialize)]
+struct HealthDocument<'a> {
+    state: &'static str,
+    readiness: Option<String>,
+    liveness: Option<String>,
+    /// Liveness checks failed in a row
+    failures: u32,
+    last_error: Option<&'a str>,
+}
+
+/// Both paths are the same file when the logs are merged
+#[derive(Serialize)]
+struct LogsDocument<'a> {
+    stdout: &'a Path,
+    stderr: &'a Path,
+}
+
+#[derive(Serialize)]
+struct ProcessDocument<'a> {
+    started: Option<String>,
+    uptime_secs: u64,
+    command: &'a [String],
+    cwd: Option<&'a Path>,
+    rss_bytes: u64,
+    cpu_percent: f64,
+}
+
+#[derive(Serialize)]
+struct DescendantDocument<'a> {
+    pid: u32,
+    command: &'a str,
+}
+
+#[derive(Serialize)]
+struct StoppedDocument<'a> {
+    name: &'a str,
+    /// not-running, signal or killed
+    how: &'static str,
+    signal: Option<String>,
+    exit: Option<ExitDocument>,
+}
+
+#[derive(Serialize)]
+struct RunDocument {
+    pid: u32,
+    reason: &'static str,
+    started: String,
+    stopped: Option<String>,
+    /// Unknown for processes the supervisor did not start itself
+    exit: Option<ExitDocument>,
+}
+
+#[derive(Serialize)]
+struct ExitDocument {
+    code: Option<i32>,
+    signal: Option<String>,
+    core_dumped: bool,
+}
+
+impl ExitDocument {
+    fn new(exit: &ExitInfo) -> Self {
+        ExitDocument {
+            code: exit.code,
+            signal: exit.signal.map(process::signal_name),
+            core_dumped: exit.core_dumped,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+struct ServiceList(Vec<ServiceRecord>);
+
+impl Record for ServiceList {
+    fn print_table(&self) {
+        if self.0.is_empty() {
+            println!("No services are tracked");
+            return;
+        }
+
+        let mut rows = vec![[
+            "NAME".to_string(),
+            "PID".to_string(),
+            "STATE".to_string(),
+            "UPTIME".to_string(),
+            "CPU".to_string(),
+            "RSS".to_string(),
+            "COMMAND".to_string(),
+        ]];
+        for ServiceRecord { service, state, process, .. } in &self.0 {
+            let pid = service.pid.map(|pid| pid.to_string()).unwrap_or_else(|| "-".to_string());
+            let state = state.to_string();
+            let row = match process {
+                Some(info) => [
+                    service.name.clone(),
+                    pid,
+                    state,
+                    format_duration(info.uptime),
+                    format!("{:.1}%", info.cpu_percent),
+                    format_bytes(info.rss_bytes),
+                    info.cmdline.join(" "),
+                ],
+                None => [
+                    service.name.clone(),
+                    pid,
+                    state,
+                    "-".to_string(),
+                    "-".to_string(),
+                    "-".to_string(),
+                    service.command_line(),
+                ],
+            };
+            rows.push(row);
+        }
+        print_columns(&rows);
+    }
+}
+
+/// Prints rows with every column as wide as its widest cell
+fn print_columns<const N: usize>(rows: &[[String; N]]) {
+    let mut widths = [0; N];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+impl Record for ServiceRecord {
+    fn print_table(&self) {
+        let ServiceRecord { service, state, process, descendants } = self;
+
+        println!("Name:    {}", service.name);
+        println!("State:   {}", state);
+        if let Some(pid) = service.pid {
+            println!("PID:     {}", pid);
+        }
+        if service.restarts > 0 {
+            println!("Restarts: {}", service.restarts);
+        }
+        match process {
+            Some(info) => {
+                println!("Uptime:  {}", format_duration(info.uptime));
+                println!("Command: {}", info.cmdline.join(" "));
+                if let Some(cwd) = &info.cwd {
+                    println!("Cwd:     {}", cwd.display());
+                }
+                println!("RSS:     {}", format_bytes(info.rss_bytes));
+                println!("CPU:     {:.1}%", info.cpu_percent);
+            }
+            None => {
+                println!("Command: {}", service.command_line());
+                if let Some(cwd) = &service.cwd {
+                    println!("Cwd:     {}", cwd.display());
+                }
+                if service.pid.is_some() {
+                    println!("The process of this service has died");
+                }
+            }
+        }
+        if service.checks.any() {
+            let health = &service.health;
+            match (&health.last_error, health.state) {
+                (Some(error), HealthState::Ready) => println!(
+                    "Health:  ready, {} failed check(s), last: {}",
+                    health.failures, error
+                ),
+                (Some(error), state) => println!("Health:  {} ({})", state.label(), error),
+                (None, state) => println!("Health:  {}", state.label()),
+            }
+        }
+        if let Some(path) = &service.cgroup_path {
+            println!("Cgroup:  {}", path.display());
+        }
+        if let Some(user) = &service.user {
+            println!("User:    {}", user);
+        }
+        if let Some(group) = &service.group {
+            println!("Group:   {}", group);
+        }
+        let limits = format_limits(&service.limits);
+        if !limits.is_empty() {
+            println!("Limits:  {}", limits);
+        }
+        if !descendants.is_empty() {
+            // Processes still around after the main one is gone were left behind
+            println!("{}", if process.is_some() { "Descendants:" } else { "Lingering:" });
+            for (pid, command) in descendants {
+                println!("  {:<7} {}", pid, command);
+            }
+        }
+        if let Some(cargo) = &service.cargo {
+            println!(
+                "Cargo:   {} from package {} ({} profile)",
+                cargo.bin, cargo.package, cargo.profile
+            );
+        }
+        if let Some(logs) = &service.logs {
+            if logs.merged() {
+                println!("Log:     {}", logs.stdout.display());
+            } else {
+                println!("Stdout:  {}", logs.stdout.display());
+                println!("Stderr:  {}", logs.stderr.display());
+            }
+        }
+    }
+}
+
+fn list_services(format: Format) -> Result<()> {
+    let services = fetch_services(None)?;
+    output::print(format, &ServiceList(services.into_iter().map(ServiceRecord::new).collect()))
+}
+
+/// The runs of one service, oldest first
+struct History(Vec<Run>);
+
+impl Serialize for History {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|run| RunDocument {
+            pid: run.pid,
+            reason: run.reason.label(),
+            started: time::format_timestamp(run.started),
+            stopped: run.stopped.map(time::format_timestamp),
+            exit: run.exit.as_ref().map(ExitDocument::new),
+        }))
+    }
+}
+
+impl Record for History {
+    fn print_table(&self) {
+        let mut rows = vec![[
+            "STARTED".to_string(),
+            "STOPPED".to_string(),
+            "RAN".to_string(),
+            "PID".to_string(),
+            "REASON".to_string(),
+            "EXIT".to_string(),
+        ]];
+        for run in &self.0 {
+            let ran = run.stopped.unwrap_or_else(SystemTime::now).duration_since(run.started);
+            let exit = match (run.stopped, run.exit) {
+                (None, _) => "running".to_string(),
+                (Some(_), Some(exit)) => exit.to_string(),
+                // The process was not a child of the supervisor that saw it go
+                (Some(_), None) => "unknown".to_string(),
+            };
+            rows.push([
+                time::format_timestamp(run.started),
+                run.stopped.map(time::format_timestamp).unwrap_or_else(|| "-".to_string()),
+                format_duration(ran.unwrap_or_default()),
+                run.pid.to_string(),
+                run.reason.label().to_string(),
+                exit,
+            ]);
+        }
+        print_columns(&rows);
+    }
+}
+
+fn service_status(name: &str, format: Format) -> Result<()> {
+    let mut services = fetch_services(Some(name))?;
+    output::print(format, &ServiceRecord::new(services.remove(0)))
+}
+
+fn service_history(name: &str, limit: Option<usize>, format: Format) -> Result<()> {
+    // Read straight from the state dir so that the runs of unregistered services show up too
+    let mut runs = history::load(name)?.ok_or_else(|| Error::NotFound(name.to_string()))?;
+    if let Some(limit) = limit {
+        runs.drain(..runs.len().saturating_sub(limit));
+    }
+    output::print(format, &History(runs))
+}
+
+fn show_logs(names: &[String], options: &LogOptions) -> Result<()> {
+    let selected = names
+        .iter()
+        .map(|name| match protocol::send(Request::Logs { name: name.clone() })? {
+            Response::Logs(logs) => Ok((name.clone(), logs)),
+            other => Err(protocol::unexpected(other)),
+        })
+        .collect::<Result<Vec<(String, Option<LogFiles>)>>>()?;
+    stdout_result(logs::show_logs(&selected, options))
+}
+
+fn show_events() -> Result<()> {
+    protocol::subscribe(|event| {
+        let mut out = io::stdout().lock();
+        stdout_result(
+            writeln!(out, "{} {}", time::format_timestamp(SystemTime::now()), event)
+                .and_then(|_| out.flush()),
+        )
+    })
+}
+
+/// A closed pipe, as with `| head`, just means nobody wants more output
+fn stdout_result(result: io::Result<()>) -> Result<()> {
+    match result {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+            Err(Error::io("Failed to write to stdout", e))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn up(manifest: Option<&Path>, format: Format) -> Result<()> {
+    let services = manifest::load_manifest(manifest)?.to_services();
+    let order = graph::topological_order(&graph::nodes(&services)).map_err(Error::Invalid)?;
+    let running: Vec<String> = fetch_services(None)?.into_iter().map(|s| s.name).collect();
+    let mut ready: Vec<&str> = Vec::new();
+    let mut started = Records::new(format);
+    let mut errors = Vec::new();
+    for name in order {
+        let service = services.iter().find(|s| s.name == name).expect("Ordered an unknown service");
+        if running.contains(&service.name) {
+            if format == Format::Table {
+                println!("Service {} is already registered", service.name);
+            }
+            continue;
+        }
+        // Dependents only start once everything they depend on is ready
+        for dependency in &service.depends_on {
+            if ready.contains(&dependency.as_str()) {
+                continue;
+            }
+            match wait_ready(dependency, format) {
+                Ok(()) => ready.push(dependency),
+                Err(e) => errors.push(e),
+            }
+        }
+        if service.depends_on.iter().all(|dependency| ready.contains(&dependency.as_str())) {
+            match start_service(service.clone()) {
+                Ok(record) => started.push(record),
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+    started.finish()?;
+    error::collect(errors)
+}
+
+fn down(manifest: Option<&Path>, timeout: Duration, format: Format) -> Result<()> {
+    let services = manifest::load_manifest(manifest)?.to_services();
+    let order = graph::topological_order(&graph::nodes(&services)).map_err(Error::Invalid)?;
+    let running: Vec<String> = fetch_services(None)?.into_iter().map(|s| s.name).collect();
+    // Dependents go first so nothing loses a dependency while it is still running
+    let mut stopped = Records::new(format);
+    let result = order
+        .into_iter()
+        .rev()
+        .filter(|name| running.iter().any(|r| r == name))
+        .try_for_each(|name| stop_service(name, libc::SIGTERM, timeout).map(|record| stopped.push(record)));
+    stopped.finish()?;
+    result
+}
+
+/// Rebuilds the service on source changes and restarts it when the build
+/// succeeds. The old process keeps running while the new one is built.
+fn watch_service(name: &str) -> Result<()> {
+    let services = fetch_services(Some(name))?;
+    let Some(target) = services[0].cargo.clone() else {
+        return Err(Error::Invalid(format!(
+            "Service {} was not started from a Cargo binary",
+            name
+        )));
+    };
+    let dirs = watch::watched_dirs(&target).map_err(Error::Other)?;
+    for dir in &dirs {
+        println!("Watching {}", dir.display());
+    }
+
+    watch::watch(&dirs, || {
+        println!("Sources changed, rebuilding {}", target.bin);
+        if let Err(e) = cargo::build(std::slice::from_ref(&target)) {
+            eprintln!("{}, keeping the running process", e);
+            return;
+        }
+        let request = Request::Restart {
+            name: name.to_string(),
+            signal: libc::SIGTERM,
+            timeout: Duration::from_secs(10),
+        };
+        match protocol::send(request) {
+            Ok(Response::Restarted { pid, .. }) => println!("Service {} restarted with pid {}", name, pid),
+            Ok(other) => eprintln!("{}", protocol::unexpected(other)),
+            Err(e) => eprintln!("{}", e),
+        }
+    });
+    Ok(())
+}
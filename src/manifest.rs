@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use ron::de::from_reader;
+use serde::{Deserialize, Deserializer};
+
+use crate::cargo;
+use crate::error::{Error, Result};
+use crate::health::{Check, HealthChecks};
+use crate::limits::ResourceLimits;
+use crate::logs::{self, LogRotation};
+use crate::process;
+use crate::service::{
+    current_dir, default_reload_signal, resolve_binary_path, resolve_path, validate_name, Service,
+};
+use crate::supervisor::{RestartPolicy, RestartSettings};
+use crate::time::parse_duration;
+
+pub const MANIFEST_NAME: &str = "Services.ron";
+
+/// A service as declared in `Services.ron` or `[package.metadata.service.<name>]`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceDefinition {
+    pub binary: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub env_file: Option<PathBuf>,
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    #[serde(default, with = "RestartDefinition")]
+    pub restart: RestartSettings,
+    /// Sent by `reload`, by name like "HUP" or "SIGUSR1"
+    #[serde(default = "default_reload_signal", deserialize_with = "deserialize_signal")]
+    pub reload_signal: libc::c_int,
+    #[serde(default)]
+    pub merge_logs: bool,
+    #[serde(default, with = "LogRotationDefinition")]
+    pub log_rotation: LogRotation,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    #[serde(default, with = "HealthChecksDefinition")]
+    pub checks: HealthChecks,
+    #[serde(default)]
+    pub cgroup: bool,
+    #[serde(default, with = "ResourceLimitsDefinition")]
+    pub limits: ResourceLimits,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+// The settings below are written the way they are given on the command line,
+// like "5s" or "on-failure", unlike in the registry
+
+/// [`RestartSettings`] as written in a manifest
+#[derive(Deserialize)]
+#[serde(remote = "RestartSettings", default = "RestartSettings::default", deny_unknown_fields)]
+struct RestartDefinition {
+    #[serde(deserialize_with = "deserialize_parsed")]
+    policy: RestartPolicy,
+    max_restarts: usize,
+    #[serde(deserialize_with = "deserialize_duration")]
+    restart_window: Duration,
+}
+
+/// [`HealthChecks`] as written in a manifest
+#[derive(Deserialize)]
+#[serde(remote = "HealthChecks", default = "HealthChecks::default", deny_unknown_fields)]
+struct HealthChecksDefinition {
+    #[serde(deserialize_with = "deserialize_optional_check")]
+    readiness: Option<Check>,
+    #[serde(deserialize_with = "deserialize_optional_check")]
+    liveness: Option<Check>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    interval: Duration,
+    #[serde(deserialize_with = "deserialize_duration")]
+    timeout: Duration,
+    retries: u32,
+    #[serde(deserialize_with = "deserialize_duration")]
+    ready_timeout: Duration,
+}
+
+/// [`LogRotation`] as written in a manifest
+#[derive(Deserialize)]
+#[serde(remote = "LogRotation", default = "LogRotation::default", deny_unknown_fields)]
+struct LogRotationDefinition {
+    #[serde(deserialize_with = "deserialize_optional_size")]
+    max_size: Option<u64>,
+    #[serde(deserialize_with = "deserialize_optional_duration")]
+    max_age: Option<Duration>,
+    keep: usize,
+    compress: bool,
+}
+
+/// [`ResourceLimits`] as written in a manifest
+#[derive(Deserialize)]
+#[serde(remote = "ResourceLimits", default = "ResourceLimits::default", deny_unknown_fields)]
+struct ResourceLimitsDefinition {
+    #[serde(deserialize_with = "deserialize_optional_size")]
+    memory: Option<u64>,
+    cpus: Option<f64>,
+    open_files: Option<u64>,
+    processes: Option<u64>,
+    #[serde(deserialize_with = "deserialize_optional_size")]
+    core_size: Option<u64>,
+}
+
+/// A size in bytes, or with a suffix like "512M"
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SizeDefinition {
+    Bytes(u64),
+    Text(String),
+}
+
+fn deserialize_with_parser<'de, D: Deserializer<'de>, T>(
+    deserializer: D,
+    parse: impl FnOnce(&str) -> std::result::Result<T, String>,
+) -> std::result::Result<T, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse(&text).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_signal<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<libc::c_int, D::Error> {
+    deserialize_with_parser(deserializer, process::parse_signal)
+}
+
+fn deserialize_parsed<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    deserialize_with_parser(deserializer, T::from_str)
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Duration, D::Error> {
+    deserialize_with_parser(deserializer, parse_duration)
+}
+
+fn deserialize_optional_with_parser<'de, D: Deserializer<'de>, T>(
+    deserializer: D,
+    parse: impl FnOnce(&str) -> std::result::Result<T, String>,
+) -> std::result::Result<Option<T>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(text) => parse(&text).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn deserialize_optional_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Duration>, D::Error> {
+    deserialize_optional_with_parser(deserializer, parse_duration)
+}
+
+fn deserialize_optional_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u64>, D::Error> {
+    match Option::<SizeDefinition>::deserialize(deserializer)? {
+        Some(SizeDefinition::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(SizeDefinition::Text(text)) => {
+            logs::parse_size(&text).map(Some).map_err(serde::de::Error::custom)
+        }
+        None => Ok(None),
+    }
+}
+
+fn deserialize_optional_check<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Check>, D::Error> {
+    deserialize_optional_with_parser(deserializer, Check::from_str)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Manifest {
+    pub services: BTreeMap<String, ServiceDefinition>,
+    /// Relative paths in the definitions are resolved against this directory
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+impl Manifest {
+    /// Turns the definitions into services ready to be started
+    pub fn to_services(&self) -> Vec<Service> {
+        self.services
+            .iter()
+            .map(|(name, definition)| {
+                let mut service = Service::new(
+                    name.clone(),
+                    resolve_binary_path(&self.base_dir, definition.binary.clone()),
+                );
+                service.args = definition.args.clone();
+                service.env = definition.env.clone();
+                service.env_file = definition.env_file.as_ref().map(|p| resolve_path(&self.base_dir, p));
+                // Services run in the directory of the manifest unless told otherwise
+                service.cwd = Some(match &definition.cwd {
+                    Some(cwd) => resolve_path(&self.base_dir, cwd),
+                    None => self.base_dir.clone(),
+                });
+                service.restart = definition.restart.clone();
+                service.reload_signal = definition.reload_signal;
+                service.depends_on = definition.depends_on.clone();
+                service.checks = definition.checks.clone();
+                service.cgroup = definition.cgroup;
+                service.limits = definition.limits.clone();
+                service.user = definition.user.clone();
+                service.group = definition.group.clone();
+                service.groups = definition.groups.clone();
+                service.logs = Some(logs::log_files(
+                    name,
+                    definition.merge_logs,
+                    definition.log_rotation.clone(),
+                ));
+                service
+            })
+            .collect()
+    }
+}
+
+fn read_ron_manifest(path: &Path) -> Result<Manifest> {
+    let file = File::open(path).map_err(|e| Error::io(format!("Failed to open {}", path.display()), e))?;
+    let mut manifest: Manifest = from_reader(file)
+        .map_err(|e| Error::Invalid(format!("Failed to parse {}: {}", path.display(), e)))?;
+    manifest.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    Ok(manifest)
+}
+
+/// Collects `[package.metadata.service.<name>]` tables from the packages of
+/// the current workspace
+fn read_cargo_manifest() -> Result<Option<Manifest>> {
+    let Ok(metadata) = cargo::metadata(None) else {
+        return Ok(None);
+    };
+
+    let mut manifest = Manifest {
+        services: BTreeMap::new(),
+        base_dir: metadata.workspace_root,
+    };
+    for package in metadata.packages {
+        let Some(table) = package.metadata.as_ref().and_then(|m| m.get("service")) else {
+            continue;
+        };
+        let services: BTreeMap<String, ServiceDefinition> = serde_json::from_value(table.clone())
+            .map_err(|e| {
+                Error::Invalid(format!(
+                    "Invalid service table in {}: {}",
+                    package.manifest_path.display(),
+                    e
+                ))
+            })?;
+        let package_dir = package.manifest_path.parent().map(Path::to_path_buf).unwrap_or_default();
+        for (name, mut definition) in services {
+            // Paths in Cargo.toml are relative to the package, not the workspace
+            definition.binary = resolve_binary_path(&package_dir, definition.binary);
+            definition.env_file = definition.env_file.map(|p| resolve_path(&package_dir, &p));
+            definition.cwd = Some(definition.cwd.map_or(package_dir.clone(), |p| resolve_path(&package_dir, &p)));
+            manifest.services.insert(name, definition);
+        }
+    }
+    Ok((!manifest.services.is_empty()).then_some(manifest))
+}
+
+/// Finds `Services.ron` in the current directory or one of its parents
+fn find_ron_manifest() -> Result<Option<PathBuf>> {
+    Ok(current_dir()?
+        .ancestors()
+        .map(|dir| dir.join(MANIFEST_NAME))
+        .find(|path| path.is_file()))
+}
+
+/// Loads the given manifest, or looks for `Services.ron` and then for service
+/// tables in `Cargo.toml`
+pub fn load_manifest(path: Option<&Path>) -> Result<Manifest> {
+    let manifest = find_manifest(path)?;
+    for name in manifest.services.keys() {
+        validate_name(name).map_err(Error::Invalid)?;
+    }
+    Ok(manifest)
+}
+
+fn find_manifest(path: Option<&Path>) -> Result<Manifest> {
+    if let Some(path) = path {
+        return read_ron_manifest(&resolve_path(&current_dir()?, path));
+    }
+    if let Some(path) = find_ron_manifest()? {
+        return read_ron_manifest(&path);
+    }
+    read_cargo_manifest()?.ok_or_else(|| {
+        Error::Invalid(format!(
+            "No {} found and no [package.metadata.service] tables in Cargo.toml",
+            MANIFEST_NAME
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ron_manifests_take_settings_as_written_on_the_command_line() {
+        let manifest: Manifest = ron::from_str(
+            r#"(services: {
+                "api": (
+                    binary: "target/debug/api",
+                    restart: (policy: "on-failure", restart_window: "5m"),
+                    reload_signal: "USR1",
+                    log_rotation: (max_size: Some("10M"), max_age: Some("1d"), keep: 3),
+                    checks: (readiness: Some("tcp:8080"), interval: "2s"),
+                    limits: (memory: Some("512M"), cpus: Some(0.5), core_size: Some(0)),
+                ),
+            })"#,
+        )
+        .unwrap();
+        let api = &manifest.services["api"];
+        assert_eq!(api.restart.policy, RestartPolicy::OnFailure);
+        assert_eq!(api.restart.restart_window, Duration::from_secs(300));
+        assert_eq!(api.reload_signal, libc::SIGUSR1);
+        assert_eq!(api.log_rotation.max_size, Some(10 << 20));
+        assert_eq!(api.log_rotation.max_age, Some(Duration::from_secs(86400)));
+        assert_eq!(api.log_rotation.keep, 3);
+        assert!(matches!(&api.checks.readiness, Some(Check::Tcp(address)) if address == "8080"));
+        assert_eq!(api.checks.interval, Duration::from_secs(2));
+        assert_eq!(api.limits.memory, Some(512 << 20));
+        assert_eq!(api.limits.cpus, Some(0.5));
+        assert_eq!(api.limits.core_size, Some(0));
+    }
+
+    #[test]
+    fn cargo_metadata_tables_take_settings_as_written_on_the_command_line() {
+        let services: BTreeMap<String, ServiceDefinition> = serde_json::from_value(serde_json::json!({
+            "worker": {
+                "binary": "worker",
+                "restart": { "policy": "always" },
+                "checks": { "liveness": "cmd:true", "timeout": "3s" },
+                "log_rotation": { "max_size": 1024, "compress": true },
+                "limits": { "memory": "2G", "processes": 64 },
+            }
+        }))
+        .unwrap();
+        let worker = &services["worker"];
+        assert_eq!(worker.restart.policy, RestartPolicy::Always);
+        assert!(matches!(&worker.checks.liveness, Some(Check::Command(command)) if command == "true"));
+        assert_eq!(worker.checks.timeout, Duration::from_secs(3));
+        assert_eq!(worker.log_rotation.max_size, Some(1024));
+        assert!(worker.log_rotation.compress);
+        assert_eq!(worker.limits.memory, Some(2 << 30));
+        assert_eq!(worker.limits.processes, Some(64));
+    }
+
+    #[test]
+    fn misspelled_settings_are_rejected() {
+        let error = ron::from_str::<Manifest>(r#"(services: {"api": (binary: "api", depend_on: ["db"])})"#)
+            .unwrap_err();
+        assert!(error.to_string().contains("depend_on"), "{}", error);
+        let error = ron::from_str::<Manifest>(r#"(services: {"api": (binary: "api", restart: (polcy: "always"))})"#)
+            .unwrap_err();
+        assert!(error.to_string().contains("polcy"), "{}", error);
+
+        let error = serde_json::from_value::<BTreeMap<String, ServiceDefinition>>(serde_json::json!({
+            "api": { "binary": "api", "limits": { "memory_limit": "1G" } }
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("memory_limit"), "{}", error);
+        let error = serde_json::from_value::<BTreeMap<String, ServiceDefinition>>(serde_json::json!({
+            "api": { "binary": "api", "restart": { "policy": "sometimes" } }
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("sometimes"), "{}", error);
+    }
+}
@@ -0,0 +1,99 @@
+use std::str::FromStr;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+/// Bumped whenever a field of a record is renamed, removed or changes its
+/// meaning. New fields do not bump it.
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// For humans, the default
+    Table,
+    Json,
+    Ron,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "ron" => Ok(Format::Ron),
+            _ => Err(format!("Unknown format {}, expected table, json or ron", s)),
+        }
+    }
+}
+
+/// What a command prints, as a table for humans or serialized for scripts
+pub trait Record: Serialize {
+    fn print_table(&self);
+}
+
+impl<T: Record> Record for Vec<T> {
+    fn print_table(&self) {
+        for record in self {
+            record.print_table();
+        }
+    }
+}
+
+/// Wraps every serialized record so that scripts can check what they got
+#[derive(Serialize)]
+struct Document<'a, T> {
+    schema_version: u32,
+    data: &'a T,
+}
+
+pub fn print<T: Record>(format: Format, record: &T) -> Result<()> {
+    let document = Document {
+        schema_version: SCHEMA_VERSION,
+        data: record,
+    };
+    let serialized = match format {
+        Format::Table => {
+            record.print_table();
+            return Ok(());
+        }
+        Format::Json => serde_json::to_string_pretty(&document).map_err(|e| e.to_string()),
+        Format::Ron => to_string_pretty(&document, PrettyConfig::new()).map_err(|e| e.to_string()),
+    };
+    let serialized = serialized.map_err(|e| Error::Other(format!("Failed to serialize output: {}", e)))?;
+    println!("{}", serialized);
+    Ok(())
+}
+
+/// The records of a command acting on several services. Tables are printed
+/// as the records come in, documents once all of them are there.
+pub struct Records<T> {
+    format: Format,
+    records: Vec<T>,
+}
+
+impl<T: Record> Records<T> {
+    pub fn new(format: Format) -> Self {
+        Records {
+            format,
+            records: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, record: T) {
+        if self.format == Format::Table {
+            record.print_table();
+        } else {
+            self.records.push(record);
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if self.format == Format::Table {
+            return Ok(());
+        }
+        print(self.format, &self.records)
+    }
+}
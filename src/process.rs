@@ -0,0 +1,380 @@
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long to wait for the process to disappear after SIGKILL
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ABRT", libc::SIGABRT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("WINCH", libc::SIGWINCH),
+];
+
+/// Parses a signal given as a number, `TERM` or `SIGTERM`
+pub fn parse_signal(s: &str) -> Result<libc::c_int, String> {
+    if let Ok(number) = s.parse::<libc::c_int>() {
+        return Ok(number);
+    }
+    let upper = s.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNALS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, signal)| *signal)
+        .ok_or_else(|| format!("Unknown signal {}", s))
+}
+
+pub fn signal_name(signal: libc::c_int) -> String {
+    SIGNALS
+        .iter()
+        .find(|(_, s)| *s == signal)
+        .map(|(name, _)| format!("SIG{}", name))
+        .unwrap_or_else(|| signal.to_string())
+}
+
+/// Sends `signal` to `pid`
+pub fn send_signal(pid: u32, signal: libc::c_int) -> io::Result<()> {
+    send_signal_raw(pid as libc::pid_t, signal)
+}
+
+/// Sends `signal` to every process in the process group `pgid`
+pub fn signal_group(pgid: u32, signal: libc::c_int) -> io::Result<()> {
+    send_signal_raw(-(pgid as libc::pid_t), signal)
+}
+
+fn send_signal_raw(target: libc::pid_t, signal: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::kill(target, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Whether a process with this pid exists and is not a zombie
+pub fn is_alive(pid: u32) -> bool {
+    if send_signal(pid, 0).is_err_and(|e| e.raw_os_error() == Some(libc::ESRCH)) {
+        return false;
+    }
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => process_state(&stat) != Some('Z'),
+        Err(_) => true,
+    }
+}
+
+/// Splits the contents of `/proc/<pid>/stat` into the fields after the command name.
+/// The first returned field is the state, which is field 3 in proc(5).
+fn stat_fields(stat: &str) -> Option<Vec<&str>> {
+    // The command name can contain spaces and parentheses, so skip past the last `)`
+    let rest = &stat[stat.rfind(')')? + 1..];
+    Some(rest.split_whitespace().collect())
+}
+
+/// Extracts the state letter from the contents of `/proc/<pid>/stat`
+fn process_state(stat: &str) -> Option<char> {
+    stat_fields(stat)?.first()?.chars().next()
+}
+
+/// What tells a process apart from a later one that got the same pid
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProcessIdentity {
+    /// Clock ticks after boot at which the process started, field 22 in proc(5)
+    pub start_time: u64,
+    pub exe: Option<PathBuf>,
+}
+
+impl ProcessIdentity {
+    /// The executable is only compared when it could be read both times,
+    /// since `/proc/<pid>/exe` of another user's process is not readable
+    fn matches(&self, other: &ProcessIdentity) -> bool {
+        self.start_time == other.start_time
+            && match (&self.exe, &other.exe) {
+                (Some(exe), Some(other)) => exe == other,
+                _ => true,
+            }
+    }
+}
+
+/// Reads the identity of a process, `None` if it is gone
+pub fn identify(pid: u32) -> Option<ProcessIdentity> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let start_time = stat_fields(&stat)?.get(19)?.parse().ok()?;
+    // A binary replaced by a rebuild shows up as `<path> (deleted)`
+    let exe = fs::read_link(format!("/proc/{}/exe", pid)).ok().map(|exe| {
+        let exe = exe.to_string_lossy();
+        PathBuf::from(exe.strip_suffix(" (deleted)").unwrap_or(&exe))
+    });
+    Some(ProcessIdentity { start_time, exe })
+}
+
+/// Whether `pid` is alive and still the process described by `identity`.
+/// Entries from before identities were recorded only have their pid checked.
+pub fn is_same_process(pid: u32, identity: Option<&ProcessIdentity>) -> bool {
+    if !is_alive(pid) {
+        return false;
+    }
+    match identity {
+        Some(identity) => identify(pid).is_some_and(|current| identity.matches(&current)),
+        None => true,
+    }
+}
+
+/// A process verified to be the one that was started. Signals go through a
+/// pidfd where the kernel supports them, so they can never reach a process
+/// that took over the pid after this one exited.
+pub struct ProcessHandle {
+    pid: u32,
+    pidfd: Option<OwnedFd>,
+    /// Signals go to the whole process group led by the process
+    group: bool,
+}
+
+impl ProcessHandle {
+    /// Opens `pid` if it still is the process described by `identity`
+    pub fn open(pid: u32, identity: Option<&ProcessIdentity>, group: bool) -> Option<ProcessHandle> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+        let pidfd = (fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) });
+        // Verified after opening the pidfd, so that the pidfd refers to the verified process
+        is_same_process(pid, identity).then_some(ProcessHandle { pid, pidfd, group })
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn signal(&self, signal: libc::c_int) -> io::Result<()> {
+        if self.group {
+            // There is no pidfd for a group, but its id cannot be reused while
+            // the leader is alive
+            if !self.is_alive() {
+                return Err(io::Error::from_raw_os_error(libc::ESRCH));
+            }
+            return signal_group(self.pid, signal);
+        }
+        let Some(pidfd) = &self.pidfd else {
+            return send_signal(self.pid, signal);
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                pidfd.as_raw_fd(),
+                signal,
+                std::ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Whether the process has not exited yet
+    pub fn is_alive(&self) -> bool {
+        let Some(pidfd) = &self.pidfd else {
+            return is_alive(self.pid);
+        };
+        // A pidfd becomes readable once the process exits
+        let mut poll = libc::pollfd {
+            fd: pidfd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut poll, 1, 0) == 0 }
+    }
+}
+
+/// Live processes in the session `sid` other than its leader, with their
+/// command lines
+pub fn session_members(sid: u32) -> Vec<(u32, String)> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut members: Vec<(u32, String)> = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| *pid != sid)
+        .filter_map(|pid| {
+            let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            let fields = stat_fields(&stat)?;
+            // The session is field 6 in proc(5)
+            if fields.first() == Some(&"Z") || fields.get(3)?.parse::<u32>().ok()? != sid {
+                return None;
+            }
+            Some((pid, command_line(pid)))
+        })
+        .collect();
+    members.sort();
+    members
+}
+
+/// The command line of a process, or its name in brackets for kernel threads
+pub fn command_line(pid: u32) -> String {
+    let cmdline = read_cmdline(pid);
+    if cmdline.is_empty() {
+        fs::read_to_string(format!("/proc/{}/comm", pid))
+            .map(|comm| format!("[{}]", comm.trim_end()))
+            .unwrap_or_default()
+    } else {
+        cmdline.join(" ")
+    }
+}
+
+fn read_cmdline(pid: u32) -> Vec<String> {
+    fs::read(format!("/proc/{}/cmdline", pid))
+        .map(|raw| {
+            raw.split(|b| *b == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// A snapshot of a running process read from `/proc/<pid>`
+#[derive(Serialize)]
+pub struct ProcessInfo {
+    pub uptime: Duration,
+    pub cmdline: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    pub rss_bytes: u64,
+    /// Average CPU usage over the lifetime of the process, 100.0 is one full core
+    pub cpu_percent: f64,
+}
+
+fn clock_ticks() -> f64 {
+    unsafe { libc::sysconf(libc::_SC_CLK_TCK) as f64 }
+}
+
+fn system_uptime() -> Option<f64> {
+    let uptime = fs::read_to_string("/proc/uptime").ok()?;
+    uptime.split_whitespace().next()?.parse().ok()
+}
+
+/// Reads the resident set size in bytes from `/proc/<pid>/status`
+fn rss_bytes(pid: u32) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Inspects a live process, returns `None` if it is gone
+pub fn inspect(pid: u32) -> Option<ProcessInfo> {
+    if !is_alive(pid) {
+        return None;
+    }
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let fields = stat_fields(&stat)?;
+    let ticks = clock_ticks();
+    let field = |index: usize| -> Option<f64> { fields.get(index)?.parse().ok() };
+
+    // utime, stime and starttime are fields 14, 15 and 22 in proc(5)
+    let cpu_time = (field(11)? + field(12)?) / ticks;
+    let started = field(19)? / ticks;
+    let uptime = (system_uptime()? - started).max(0.0);
+    let cpu_percent = if uptime > 0.0 { cpu_time / uptime * 100.0 } else { 0.0 };
+
+    let cmdline = read_cmdline(pid);
+
+    Some(ProcessInfo {
+        uptime: Duration::from_secs_f64(uptime),
+        cmdline,
+        cwd: fs::read_link(format!("/proc/{}/cwd", pid)).ok(),
+        rss_bytes: rss_bytes(pid).unwrap_or(0),
+        cpu_percent,
+    })
+}
+
+/// Reaps `pid` if it is our child and has exited.
+/// Returns `None` while it is still running or when it is not our child.
+fn try_reap(pid: u32) -> Option<ExitStatus> {
+    let mut status = 0;
+    let result = unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) };
+    if result == pid as libc::pid_t {
+        Some(ExitStatus::from_raw(status))
+    } else {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum StopPath {
+    /// The process was already gone before we signaled it
+    NotRunning,
+    /// The process exited after the first signal
+    Graceful(libc::c_int),
+    /// The process outlived the timeout and was sent SIGKILL
+    Killed,
+}
+
+pub struct StopOutcome {
+    pub path: StopPath,
+    /// Only known when the process was a child of this process
+    pub status: Option<ExitStatus>,
+}
+
+/// Waits until the process exits or the timeout expires.
+/// Returns `Err(())` if it is still running afterwards.
+fn wait_for_exit(process: &ProcessHandle, timeout: Duration) -> Result<Option<ExitStatus>, ()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = try_reap(process.pid) {
+            return Ok(Some(status));
+        }
+        if !process.is_alive() {
+            return Ok(None);
+        }
+        if Instant::now() >= deadline {
+            return Err(());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Sends `signal`, waits up to `timeout` for the process to exit and
+/// escalates to SIGKILL if it is still alive
+pub fn terminate(process: &ProcessHandle, signal: libc::c_int, timeout: Duration) -> io::Result<StopOutcome> {
+    if !process.is_alive() {
+        return Ok(StopOutcome {
+            path: StopPath::NotRunning,
+            status: try_reap(process.pid),
+        });
+    }
+
+    process.signal(signal)?;
+    if let Ok(status) = wait_for_exit(process, timeout) {
+        return Ok(StopOutcome {
+            path: StopPath::Graceful(signal),
+            status,
+        });
+    }
+
+    process.signal(libc::SIGKILL)?;
+    let status = wait_for_exit(process, KILL_TIMEOUT).map_err(|_| {
+        io::Error::new(io::ErrorKind::TimedOut, "Process survived SIGKILL")
+    })?;
+    Ok(StopOutcome {
+        path: StopPath::Killed,
+        status,
+    })
+}
@@ -0,0 +1,242 @@
+//! The protocol spoken between the CLI (or any other tool) and the supervisor.
+//!
+//! Every message is a single line of RON wrapped in an [`Envelope`]. A client
+//! sends one request per line and gets one response line back, except for
+//! [`Request::Subscribe`] after which the supervisor keeps sending events.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+use ron::de::from_str;
+use ron::ser::to_string;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::logs::LogFiles;
+use crate::process::StopPath;
+use crate::dirs::get_runtime_dir;
+use crate::service::Service;
+use crate::supervisor;
+
+/// Bumped on every incompatible change to the messages below
+pub const PROTOCOL_VERSION: u32 = 5;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope<T> {
+    pub version: u32,
+    pub body: T,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    /// Registers a service and spawns it with the environment of the caller
+    Start {
+        service: Box<Service>,
+        env: BTreeMap<String, String>,
+    },
+    /// Stops a service and unregisters it
+    Stop {
+        name: String,
+        signal: i32,
+        timeout: Duration,
+    },
+    /// Stops a service and spawns it again with the same invocation
+    Restart {
+        name: String,
+        signal: i32,
+        timeout: Duration,
+    },
+    /// Sends the reload signal of a service to its process
+    Reload { name: String },
+    /// All services, or only the named one
+    Status { name: Option<String> },
+    /// Where the output of a service is captured
+    Logs { name: String },
+    /// Waits until the service passed its readiness check
+    WaitReady { name: String },
+    /// Keeps the connection open and streams [`Event`]s
+    Subscribe,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    Started { pid: u32 },
+    Stopped {
+        path: StopPath,
+        exit: Option<ExitInfo>,
+    },
+    Restarted {
+        path: StopPath,
+        exit: Option<ExitInfo>,
+        pid: u32,
+    },
+    Reloaded { pid: u32, signal: i32 },
+    Services(Vec<Service>),
+    Logs(Option<LogFiles>),
+    Ready,
+    Subscribed,
+    Event(Event),
+    Error(Error),
+}
+
+/// How a process ended
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ExitInfo {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub core_dumped: bool,
+}
+
+impl From<ExitStatus> for ExitInfo {
+    fn from(status: ExitStatus) -> Self {
+        ExitInfo {
+            code: status.code(),
+            signal: status.signal(),
+            core_dumped: status.core_dumped(),
+        }
+    }
+}
+
+impl ExitInfo {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+impl fmt::Display for ExitInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(f, "exit code {}", code)?,
+            (None, Some(signal)) => {
+                write!(f, "terminated by {}", crate::process::signal_name(signal))?
+            }
+            (None, None) => write!(f, "unknown")?,
+        }
+        if self.core_dumped {
+            write!(f, " (core dumped)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Something that happened to a service, sent to subscribers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Event {
+    Started { name: String, pid: u32 },
+    Exited {
+        name: String,
+        pid: u32,
+        exit: Option<ExitInfo>,
+    },
+    Restarting { name: String, delay: Duration },
+    GaveUp { name: String },
+    Ready { name: String },
+    Unhealthy { name: String, error: String },
+    Reloaded { name: String, signal: i32 },
+    Stopped { name: String },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Started { name, pid } => write!(f, "{} started with pid {}", name, pid),
+            Event::Exited { name, pid, exit: Some(exit) } => {
+                write!(f, "{} (pid {}) exited: {}", name, pid, exit)
+            }
+            Event::Exited { name, pid, exit: None } => write!(f, "{} (pid {}) is gone", name, pid),
+            Event::Restarting { name, delay } => {
+                write!(f, "{} restarting in {}ms", name, delay.as_millis())
+            }
+            Event::GaveUp { name } => write!(f, "{} restarted too often, giving up", name),
+            Event::Ready { name } => write!(f, "{} is ready", name),
+            Event::Unhealthy { name, error } => write!(f, "{} is unhealthy: {}", name, error),
+            Event::Reloaded { name, signal } => {
+                write!(f, "{} reloaded with {}", name, crate::process::signal_name(*signal))
+            }
+            Event::Stopped { name } => write!(f, "{} stopped", name),
+        }
+    }
+}
+
+pub fn get_socket_path() -> PathBuf {
+    get_runtime_dir().join("supervisor.sock")
+}
+
+/// Writes one message as a line
+pub fn write_message<T: Serialize>(stream: &mut impl Write, body: T) -> std::io::Result<()> {
+    let envelope = Envelope {
+        version: PROTOCOL_VERSION,
+        body,
+    };
+    let mut line = to_string(&envelope).map_err(std::io::Error::other)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    stream.flush()
+}
+
+/// Reads one message, `Ok(None)` when the other side closed the connection
+pub fn read_message<T: DeserializeOwned>(stream: &mut impl BufRead) -> Result<Option<T>> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).map_err(|e| Error::io("Failed to read message", e))? == 0 {
+        return Ok(None);
+    }
+    let malformed = |e: ron::error::SpannedError| Error::Supervisor(format!("Malformed message: {}", e));
+    // Check the version on its own first so that a newer peer gets a clear error
+    #[derive(Deserialize)]
+    struct Version {
+        version: u32,
+    }
+    let Version { version } = from_str(&line).map_err(malformed)?;
+    if version != PROTOCOL_VERSION {
+        return Err(Error::Supervisor(format!(
+            "Protocol version mismatch, expected {} but got {}. Restart the supervisor after upgrading.",
+            PROTOCOL_VERSION, version
+        )));
+    }
+    let envelope: Envelope<T> = from_str(&line).map_err(malformed)?;
+    Ok(Some(envelope.body))
+}
+
+fn connect(request: Request) -> Result<BufReader<UnixStream>> {
+    supervisor::ensure_running()?;
+    let unreachable = |e| Error::Supervisor(format!("Failed to talk to the supervisor: {}", e));
+    let mut stream = UnixStream::connect(get_socket_path()).map_err(unreachable)?;
+    write_message(&mut stream, request).map_err(unreachable)?;
+    Ok(BufReader::new(stream))
+}
+
+/// The error for a response that does not fit the request
+pub fn unexpected(response: Response) -> Error {
+    Error::Supervisor(format!("Unexpected response from supervisor: {:?}", response))
+}
+
+/// Sends a request to the supervisor, starting it if needed, and waits for the
+/// response. An error response becomes the `Err`.
+pub fn send(request: Request) -> Result<Response> {
+    let mut stream = connect(request)?;
+    match read_message(&mut stream)? {
+        Some(Response::Error(error)) => Err(error),
+        Some(response) => Ok(response),
+        None => Err(Error::Supervisor("The supervisor closed the connection".to_string())),
+    }
+}
+
+/// Subscribes to events and calls `on_event` for each one until the supervisor goes away
+pub fn subscribe(mut on_event: impl FnMut(Event) -> Result<()>) -> Result<()> {
+    let mut stream = connect(Request::Subscribe)?;
+    while let Some(response) = read_message(&mut stream)? {
+        match response {
+            Response::Event(event) => on_event(event)?,
+            Response::Subscribed => {}
+            Response::Error(error) => return Err(error),
+            other => return Err(unexpected(other)),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,636 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use ron::de::from_reader;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+
+use crate::cargo::CargoTarget;
+use crate::cgroup;
+use crate::dirs::get_state_dir;
+use crate::error::{Error, Result};
+use crate::health::{Health, HealthChecks};
+use crate::limits::{self, ResourceLimits};
+use crate::logs::{self, LogFiles};
+use crate::process::{self, ProcessIdentity};
+use crate::supervisor::RestartSettings;
+use crate::user::{self, Credentials};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ServiceState {
+    /// Waiting for the supervisor to spawn it
+    Pending,
+    #[default]
+    Running,
+    /// Exited and not restarted because of its restart policy
+    Exited,
+    /// Waiting to be restarted after exiting
+    Backoff,
+    /// Could not be spawned, or restarted too often within the restart window
+    Failed,
+}
+
+impl ServiceState {
+    pub fn label(self) -> &'static str {
+        match self {
+            ServiceState::Pending => "pending",
+            ServiceState::Running => "running",
+            ServiceState::Exited => "exited",
+            ServiceState::Backoff => "restarting",
+            ServiceState::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Service {
+    /// Entries written before services had names are migrated on load
+    #[serde(default)]
+    pub name: String,
+    pub binary_path: String,
+    pub pid: Option<u32>,
+    /// Recorded at spawn so that a recycled pid is not taken for the service
+    #[serde(default)]
+    pub identity: Option<ProcessIdentity>,
+    /// The session the process was started in, kept after it exits to find
+    /// the processes it left behind. Older entries were not started in their own.
+    #[serde(default)]
+    pub session: Option<u32>,
+    /// Whether to run the service in its own cgroup v2 subtree
+    #[serde(default)]
+    pub cgroup: bool,
+    #[serde(default)]
+    pub cgroup_path: Option<PathBuf>,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// The user to run as, by name or id
+    #[serde(default)]
+    pub user: Option<String>,
+    /// The group to run as, defaults to the primary group of `user`
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Supplementary groups, default to the groups of `user`
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Variables set with `--env`, applied on top of `env_file`
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub env_file: Option<PathBuf>,
+    /// The environment of the CLI that started the service. It may hold
+    /// secrets, so it only lives in the memory of the supervisor, and services
+    /// restarted by a later supervisor get the environment of that one.
+    #[serde(skip)]
+    pub caller_env: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    /// Entries from before output was captured have no log files
+    #[serde(default)]
+    pub logs: Option<LogFiles>,
+    #[serde(default)]
+    pub restart: RestartSettings,
+    /// Sent by `reload` to make the service reread its configuration
+    #[serde(default = "default_reload_signal")]
+    pub reload_signal: libc::c_int,
+    #[serde(default)]
+    pub state: ServiceState,
+    /// How often the supervisor restarted the service since it was started
+    #[serde(default)]
+    pub restarts: u32,
+    /// Set when the binary is built from a Cargo project
+    #[serde(default)]
+    pub cargo: Option<CargoTarget>,
+    /// Services that have to be running before this one is started
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    #[serde(default)]
+    pub checks: HealthChecks,
+    /// The outcome of the health checks of the current process
+    #[serde(default)]
+    pub health: Health,
+}
+
+pub fn default_reload_signal() -> libc::c_int {
+    libc::SIGHUP
+}
+
+impl Service {
+    /// A service that has not been started yet, with no extra settings
+    pub fn new(name: String, binary_path: String) -> Service {
+        Service {
+            name,
+            binary_path,
+            pid: None,
+            identity: None,
+            session: None,
+            cgroup: false,
+            cgroup_path: None,
+            limits: ResourceLimits::default(),
+            user: None,
+            group: None,
+            groups: Vec::new(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            env_file: None,
+            caller_env: None,
+            cwd: None,
+            logs: None,
+            restart: RestartSettings::default(),
+            reload_signal: default_reload_signal(),
+            state: ServiceState::Pending,
+            restarts: 0,
+            cargo: None,
+            depends_on: Vec::new(),
+            checks: HealthChecks::default(),
+            health: Health::default(),
+        }
+    }
+
+    /// Who to run the process as, `None` to keep the credentials of the supervisor
+    pub fn credentials(&self) -> Result<Option<Credentials>> {
+        if self.user.is_none() && self.group.is_none() && self.groups.is_empty() {
+            return Ok(None);
+        }
+        if unsafe { libc::geteuid() } != 0 {
+            return Err(Error::PermissionDenied(format!(
+                "Only root can run service {} as another user or group",
+                self.name
+            )));
+        }
+        user::resolve(self.user.as_deref(), self.group.as_deref(), &self.groups)
+            .map(Some)
+            .map_err(Error::Invalid)
+    }
+
+    /// The pid of the process, if it is still running and still the one that was spawned
+    pub fn running_pid(&self) -> Option<u32> {
+        self.pid.filter(|pid| process::is_same_process(*pid, self.identity.as_ref()))
+    }
+
+    /// Whether the current process leads its own session and process group,
+    /// so that signals can go to the whole group
+    pub fn leads_session(&self) -> bool {
+        self.pid.is_some() && self.session == self.pid
+    }
+
+    /// The session the processes of the service are in. Once its leader is
+    /// gone, the id can be taken by the leader of an unrelated session.
+    fn current_session(&self) -> Option<u32> {
+        let sid = self.session?;
+        if process::identify(sid).is_none() {
+            // What is left of the session is ours
+            return Some(sid);
+        }
+        let ours = self.pid == Some(sid) && process::is_same_process(sid, self.identity.as_ref());
+        ours.then_some(sid)
+    }
+
+    /// Processes the service started that are still alive, other than its main process
+    pub fn descendants(&self) -> Vec<(u32, String)> {
+        let mut descendants = self.current_session().map(process::session_members).unwrap_or_default();
+        // The cgroup also catches processes that left the session
+        if let Some(path) = &self.cgroup_path {
+            for pid in cgroup::members(path) {
+                if Some(pid) != self.pid && !descendants.iter().any(|(p, _)| *p == pid) {
+                    descendants.push((pid, process::command_line(pid)));
+                }
+            }
+        }
+        descendants.sort();
+        descendants
+    }
+
+    /// Forgets the session of a process that is gone once nothing is left in
+    /// it, before its id can be reused. Returns whether it was forgotten.
+    pub fn forget_empty_session(&mut self) -> bool {
+        let empty = self.pid.is_none() && self.session.is_some() && self.descendants().is_empty();
+        if empty {
+            self.session = None;
+        }
+        empty
+    }
+
+    /// Kills whatever a previous run of the service left behind
+    pub fn kill_descendants(&self) {
+        if let Some(path) = &self.cgroup_path {
+            cgroup::kill(path);
+        }
+        for (pid, _) in self.descendants() {
+            let _ = process::send_signal(pid, libc::SIGKILL);
+        }
+    }
+
+    /// The stored invocation as it would be typed in a shell, without quoting
+    pub fn command_line(&self) -> String {
+        let mut parts = vec![self.binary_path.as_str()];
+        parts.extend(self.args.iter().map(String::as_str));
+        parts.join(" ")
+    }
+}
+
+/// Derives a service name from the file name of its binary
+pub fn default_name(binary_path: &str) -> String {
+    Path::new(binary_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| binary_path.to_string())
+}
+
+/// Checks that a name can be used in the names of files and cgroups: letters,
+/// digits, `.`, `_` and `-`, but not `.` or `..` alone
+pub fn validate_name(name: &str) -> std::result::Result<(), String> {
+    if name.is_empty() {
+        return Err("The name of a service must not be empty".to_string());
+    }
+    if name == "." || name == ".." {
+        return Err(format!("{} is not a valid service name", name));
+    }
+    match name.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))) {
+        Some(c) => Err(format!(
+            "Invalid character {:?} in service name {}, only letters, digits, '.', '_' and '-' are allowed",
+            c, name
+        )),
+        None => Ok(()),
+    }
+}
+
+pub fn parse_name(s: &str) -> std::result::Result<String, String> {
+    validate_name(s).map(|()| s.to_string())
+}
+
+pub fn current_dir() -> Result<PathBuf> {
+    std::env::current_dir().map_err(|e| Error::io("Failed to get the current directory", e))
+}
+
+/// Resolves a relative path against `base`
+pub fn resolve_path(base: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        base.join(path)
+    }
+}
+
+/// Binaries given as a bare name are looked up in `PATH`, anything else is a
+/// path relative to `base`
+pub fn resolve_binary_path(base: &Path, binary_path: String) -> String {
+    if binary_path.contains('/') {
+        resolve_path(base, Path::new(&binary_path)).to_string_lossy().into_owned()
+    } else {
+        binary_path
+    }
+}
+
+pub fn parse_env_var(s: &str) -> std::result::Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Expected KEY=VALUE, got {}", s)),
+    }
+}
+
+/// Reads a dotenv style file, skipping blank lines and `#` comments
+fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let context = || format!("Failed to read env file {}", path.display());
+    let file = File::open(path).map_err(|e| Error::io(context(), e))?;
+    let mut vars = Vec::new();
+
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| Error::io(context(), e))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = parse_env_var(line)
+            .map_err(|e| Error::Invalid(format!("{}:{}: {}", path.display(), number + 1, e)))?;
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+            .unwrap_or(value);
+        vars.push((key.trim().to_string(), value.to_string()));
+    }
+
+    Ok(vars)
+}
+
+/// Builds the command for a service from its stored invocation
+pub fn service_command(service: &Service, credentials: Option<&Credentials>) -> Result<Command> {
+    let mut command = Command::new(&service.binary_path);
+    command.args(&service.args);
+    if let Some(caller_env) = &service.caller_env {
+        command.env_clear().envs(caller_env);
+    }
+    // Like login would, the variables of the service still take precedence
+    if let Some((name, home)) = credentials.and_then(|credentials| credentials.user.as_ref()) {
+        command.env("USER", name).env("LOGNAME", name).env("HOME", home);
+    }
+    if let Some(env_file) = &service.env_file {
+        command.envs(read_env_file(env_file)?);
+    }
+    command.envs(&service.env);
+    if let Some(cwd) = &service.cwd {
+        command.current_dir(cwd);
+    }
+    Ok(command)
+}
+
+/// Spawns the process of a service in a session of its own, with its output
+/// going to its log files, and returns its pid. The caller is responsible for
+/// reaping it.
+pub fn spawn_service(service: &mut Service) -> Result<u32> {
+    let failed = |name: &str, reason: String| Error::SpawnFailed {
+        name: name.to_string(),
+        reason,
+    };
+    let (stdout, stderr) = match &service.logs {
+        Some(logs) => logs::open_log_files(logs)
+            .map_err(|e| failed(&service.name, format!("Failed to open log files: {}", e)))?,
+        None => (Stdio::null(), Stdio::null()),
+    };
+    let credentials = service.credentials()?;
+    service.cgroup_path = if service.cgroup || service.limits.wants_cgroup() {
+        match cgroup::create(&service.name) {
+            Ok(path) => Some(path),
+            Err(e) if service.cgroup => {
+                return Err(Error::io(format!("Failed to create cgroup for {}", service.name), e));
+            }
+            // Limits alone only use a cgroup when one can be had
+            Err(e) => {
+                println!("No cgroup for {}, limiting it with rlimits: {}", service.name, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let in_cgroup = match (&service.cgroup_path, service.limits.wants_cgroup()) {
+        (Some(path), true) => match service.limits.apply_cgroup(path) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Failed to set cgroup limits of {}, using rlimits: {}", service.name, e);
+                false
+            }
+        },
+        (path, _) => path.is_some(),
+    };
+    let rlimits = service.limits.rlimits(in_cgroup);
+    for rlimit in &rlimits {
+        // Raising a hard limit fails in the child with a less helpful error
+        let hard = limits::hard_limit(rlimit.resource)
+            .map_err(|e| Error::io(format!("Failed to read the {} limit", rlimit.name), e))?;
+        if rlimit.value > hard && unsafe { libc::geteuid() } != 0 {
+            return Err(Error::PermissionDenied(format!(
+                "The {} of {} is {}, above the hard limit of {}",
+                rlimit.name, service.name, rlimit.value, hard
+            )));
+        }
+    }
+    let procs = service
+        .cgroup_path
+        .as_deref()
+        .map(cgroup::open_procs)
+        .transpose()
+        .map_err(|e| Error::io(format!("Failed to join cgroup of {}", service.name), e))?;
+    let procs_fd = procs.as_ref().map(|procs| procs.as_raw_fd());
+
+    let mut command = service_command(service, credentials.as_ref())?;
+    command.stdin(Stdio::null()).stdout(stdout).stderr(stderr);
+    // Joining the cgroup before exec means every descendant starts inside it
+    unsafe {
+        command.pre_exec(move || {
+            libc::setsid();
+            limits::set_rlimits(&rlimits)?;
+            if let Some(fd) = procs_fd {
+                if libc::write(fd, b"0".as_ptr().cast(), 1) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            // Last, everything before needs the privileges of the supervisor
+            if let Some(credentials) = &credentials {
+                user::drop_privileges(credentials)?;
+            }
+            Ok(())
+        });
+    }
+    let pid = match command.spawn() {
+        Ok(child) => child.id(),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            return Err(Error::io(format!("Failed to run {}", service.binary_path), e))
+        }
+        Err(e) => return Err(failed(&service.name, e.to_string())),
+    };
+    service.session = Some(pid);
+    Ok(pid)
+}
+
+/// Held while the registry is read or written, so that concurrent calls do
+/// not interleave. The lock is released when the file is dropped.
+fn lock_registry() -> Result<File> {
+    let path = get_state_dir().join("cache.ron.lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|e| Error::io(format!("Failed to open {}", path.display()), e))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(Error::io(format!("Failed to lock {}", path.display()), e));
+    }
+    Ok(file)
+}
+
+/// Reads a registry file, `None` if it does not exist
+fn read_registry(path: &Path) -> Result<Option<Vec<Service>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::io(format!("Failed to open {}", path.display()), e)),
+    };
+    from_reader(file).map(Some).map_err(|e| Error::StateCorrupt {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })
+}
+
+/// Replaces the registry without ever leaving a half written file behind.
+/// With `update_backup` the backup to recover from is replaced as well.
+fn write_registry(services: &[Service], update_backup: bool) -> Result<()> {
+    let path = get_registry_path();
+    let pretty = PrettyConfig::new();
+    let data = to_string_pretty(services, pretty)
+        .map_err(|e| Error::Other(format!("Failed to serialize services: {}", e)))?;
+    // The supervisor and the CLI both read the registry, so never let them
+    // see a half written file
+    let tmp = path.with_extension(format!("ron.{}.tmp", std::process::id()));
+    let replace = |target: &Path| -> std::io::Result<()> {
+        // Variables set with --env may be secrets
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+        file.write_all(data.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, target)
+    };
+    let write = || -> std::io::Result<()> {
+        replace(&path)?;
+        // A separate copy, not a link, so that damage to one leaves the other
+        if update_backup {
+            replace(&get_backup_path())?;
+        }
+        // The renames only survive a crash once the directory is synced
+        File::open(get_state_dir())?.sync_all()
+    };
+    write().map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        Error::io(format!("Failed to write {}", path.display()), e)
+    })
+}
+
+pub fn load_services() -> Result<Vec<Service>> {
+    let _lock = lock_registry()?;
+    let path = get_registry_path();
+    let mut services = match read_registry(&path) {
+        Ok(services) => services.unwrap_or_default(),
+        Err(Error::StateCorrupt { path, reason }) => {
+            let backup = get_backup_path();
+            let Ok(Some(services)) = read_registry(&backup) else {
+                return Err(Error::StateCorrupt { path, reason });
+            };
+            // Keep the broken file around for inspection
+            let corrupt = path.with_extension("ron.corrupt");
+            fs::rename(&path, &corrupt)
+                .map_err(|e| Error::io(format!("Failed to move {} away", path.display()), e))?;
+            eprintln!(
+                "{} is corrupt ({}), moved it to {} and recovered {} service(s) from {}",
+                path.display(),
+                reason,
+                corrupt.display(),
+                services.len(),
+                backup.display()
+            );
+            write_registry(&services, false)?;
+            services
+        }
+        Err(e) => return Err(e),
+    };
+    if migrate_names(&mut services) {
+        write_registry(&services, true)?;
+    }
+    Ok(services)
+}
+
+pub fn save_services(services: &[Service]) -> Result<()> {
+    let _lock = lock_registry()?;
+    write_registry(services, true)
+}
+
+/// Gives unnamed entries from older caches a unique name based on their binary.
+/// Returns whether anything was changed.
+fn migrate_names(services: &mut [Service]) -> bool {
+    let mut taken: HashSet<String> = services
+        .iter()
+        .filter(|s| !s.name.is_empty())
+        .map(|s| s.name.clone())
+        .collect();
+    let mut migrated = false;
+
+    for service in services.iter_mut().filter(|s| s.name.is_empty()) {
+        let base = default_name(&service.binary_path);
+        let mut name = base.clone();
+        let mut suffix = 1;
+        while taken.contains(&name) {
+            suffix += 1;
+            name = format!("{}-{}", base, suffix);
+        }
+        taken.insert(name.clone());
+        service.name = name;
+        migrated = true;
+    }
+
+    migrated
+}
+
+
+pub fn get_registry_path() -> PathBuf {
+    get_state_dir().join("cache.ron")
+}
+
+/// A copy of the registry to recover from
+fn get_backup_path() -> PathBuf {
+    get_state_dir().join("cache.ron.bak")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirs;
+
+    #[test]
+    fn corrupt_registry_is_recovered_from_the_backup() {
+        let home = std::env::temp_dir().join(format!("cargo-service-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&home);
+        dirs::init(Some(home.clone()), false).unwrap();
+        let services = vec![
+            Service::new("api".to_string(), "/bin/api".to_string()),
+            Service::new("web".to_string(), "/bin/web".to_string()),
+        ];
+        save_services(&services).unwrap();
+        fs::write(get_registry_path(), "[(name: \"api\", binary_pa").unwrap();
+
+        let loaded = load_services().unwrap();
+        let names: Vec<&str> = loaded.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["api", "web"]);
+        assert!(home.join("cache.ron.corrupt").exists());
+        // The registry itself is whole again
+        assert_eq!(read_registry(&get_registry_path()).unwrap().unwrap().len(), 2);
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn unnamed_entries_get_unique_names() {
+        let mut services = vec![
+            Service::new(String::new(), "/a/server".to_string()),
+            Service::new("server".to_string(), "/bin/other".to_string()),
+            Service::new(String::new(), "/b/server".to_string()),
+            Service::new(String::new(), "/usr/bin/worker".to_string()),
+        ];
+        assert!(migrate_names(&mut services));
+        let names: Vec<&str> = services.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["server-2", "server", "server-3", "worker"]);
+        assert!(!migrate_names(&mut services));
+    }
+
+    #[test]
+    fn names_stay_inside_their_directories() {
+        for name in ["api", "web-2", "worker_a", "v1.2", ".hidden"] {
+            assert_eq!(validate_name(name), Ok(()), "{}", name);
+        }
+        for name in ["", ".", "..", "../escaped", "a/b", "my service", "tab\t", "caf\u{e9}"] {
+            assert!(validate_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn reused_session_ids_are_not_ours() {
+        // The id of an exited leader, now taken by this test's process
+        let mut service = Service::new("api".to_string(), "/bin/api".to_string());
+        service.session = Some(std::process::id());
+        assert_eq!(service.current_session(), None);
+        assert!(service.forget_empty_session());
+        assert_eq!(service.session, None);
+
+        service.pid = Some(std::process::id());
+        service.identity = process::identify(std::process::id());
+        service.session = service.pid;
+        assert_eq!(service.current_session(), service.pid);
+        assert!(!service.forget_empty_session());
+    }
+}
@@ -0,0 +1,1071 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::cgroup;
+use crate::error::{Error, Result};
+use crate::health::{Health, HealthState, Probe};
+use crate::history::{self, StartReason};
+use crate::process::{self, ProcessHandle, StopPath};
+use crate::protocol::{self, get_socket_path, Event, ExitInfo, Request, Response};
+use crate::dirs::{self, get_runtime_dir, get_state_dir};
+use crate::service::{load_services, save_services, spawn_service, validate_name, Service, ServiceState};
+use crate::time::{self, parse_duration};
+
+const TICK: Duration = Duration::from_millis(200);
+
+/// How often services started by a previous supervisor are checked for being alive
+const ADOPTED_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long to wait for a process to disappear after SIGKILL
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the CLI waits for a freshly spawned supervisor to come up
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First delay before restarting a service, doubled on every restart in a row
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How often the readiness check of a starting service is retried
+const READY_INTERVAL: Duration = Duration::from_millis(500);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn label(self) -> &'static str {
+        match self {
+            RestartPolicy::Never => "never",
+            RestartPolicy::OnFailure => "on-failure",
+            RestartPolicy::Always => "always",
+        }
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(RestartPolicy::Never),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            _ => Err(format!("Unknown restart policy {}, expected never, on-failure or always", s)),
+        }
+    }
+}
+
+// When the supervisor restarts a service after it exits
+#[derive(StructOpt, Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RestartSettings {
+    /// Restart the service when it exits: never, on-failure or always
+    #[structopt(long = "restart", default_value = "never")]
+    pub policy: RestartPolicy,
+    /// Give up after this many restarts within the restart window
+    #[structopt(long, default_value = "5")]
+    pub max_restarts: usize,
+    /// The window in which restarts are counted, e.g. 60s or 5m
+    #[structopt(long, default_value = "60s", parse(try_from_str = parse_duration))]
+    pub restart_window: Duration,
+}
+
+impl Default for RestartSettings {
+    fn default() -> Self {
+        RestartSettings {
+            policy: RestartPolicy::Never,
+            max_restarts: 5,
+            restart_window: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RestartSettings {
+    fn should_restart(&self, exit: Option<ExitInfo>) -> bool {
+        match self.policy {
+            RestartPolicy::Never => false,
+            // A service whose exit status we could not observe counts as failed
+            RestartPolicy::OnFailure => !exit.is_some_and(|exit| exit.success()),
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+fn get_pid_path() -> PathBuf {
+    get_runtime_dir().join("supervisor.pid")
+}
+
+fn get_lock_path() -> PathBuf {
+    get_runtime_dir().join("supervisor.lock")
+}
+
+pub fn get_log_path() -> PathBuf {
+    get_state_dir().join("supervisor.log")
+}
+
+/// Makes sure a supervisor is accepting connections, starting one if needed
+// The supervisor is detached on purpose, it outlives the CLI
+#[allow(clippy::zombie_processes)]
+pub fn ensure_running() -> Result<()> {
+    if UnixStream::connect(get_socket_path()).is_ok() {
+        return Ok(());
+    }
+    // Report a broken registry here rather than as a supervisor that dies on startup
+    load_services()?;
+
+    let exe = env::current_exe().map_err(|e| Error::io("Failed to find the cargo-service executable", e))?;
+    let log_path = get_log_path();
+    let open_log = || {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .map_err(|e| Error::io(format!("Failed to open {}", log_path.display()), e))
+    };
+    let mut command = Command::new(exe);
+    command
+        .arg("supervise")
+        .args(dirs::supervisor_args())
+        .stdin(Stdio::null())
+        .stdout(open_log()?)
+        .stderr(open_log()?);
+    // Leave the terminal's session so the supervisor survives the shell exiting
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    command
+        .spawn()
+        .map_err(|e| Error::Supervisor(format!("Failed to start the supervisor: {}", e)))?;
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while Instant::now() < deadline {
+        if UnixStream::connect(get_socket_path()).is_ok() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Err(Error::Supervisor(format!(
+        "The supervisor did not start, see {}",
+        log_path.display()
+    )))
+}
+
+extern "C" fn on_shutdown(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Takes an exclusive lock that is held for as long as the returned file is open
+fn lock_instance() -> io::Result<File> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(get_lock_path())?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        Ok(file)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// A service process spawned and owned by the supervisor
+struct Child {
+    pid: u32,
+    started: Instant,
+    /// Set while a client is stopping the service, receives its exit status
+    /// instead of the restart policy being applied
+    stopping: Option<Sender<ExitStatus>>,
+}
+
+/// Health check bookkeeping of a running service
+struct Checking {
+    pid: u32,
+    /// Log lines from before the process started do not make it ready
+    started: SystemTime,
+    /// When the readiness check gives up
+    ready_deadline: Instant,
+    /// When the next check is due, `None` while one is running
+    due: Option<Instant>,
+}
+
+/// How a running service is stopped
+enum StopTarget {
+    /// Our own child, its exit status arrives through the receiver. The flag
+    /// tells whether signals go to its whole process group.
+    Owned(u32, bool, Receiver<ExitStatus>),
+    /// Started by a previous supervisor, can only be signaled and polled
+    Adopted(ProcessHandle),
+    NotRunning,
+}
+
+#[derive(Default)]
+struct Supervisor {
+    /// The registry, only ever written by the supervisor
+    services: Vec<Service>,
+    children: HashMap<String, Child>,
+    /// Services waiting to be restarted and when to do so
+    restart_at: HashMap<String, Instant>,
+    /// Recent restarts of each service, to detect crash loops
+    restart_times: HashMap<String, VecDeque<Instant>>,
+    subscribers: Vec<Sender<Event>>,
+    /// Services with health checks whose process is running
+    checks: HashMap<String, Checking>,
+    /// Pids of running check commands, with their exit status once reaped
+    check_commands: HashMap<u32, Option<ExitStatus>>,
+    /// Services being stopped, which stay registered until their process is gone
+    stopping: HashSet<String>,
+}
+
+impl Supervisor {
+    /// Picks up the registry left by a previous supervisor
+    fn load() -> Result<Supervisor> {
+        let mut supervisor = Supervisor {
+            services: load_services()?,
+            ..Supervisor::default()
+        };
+        let now = Instant::now();
+        for index in 0..supervisor.services.len() {
+            let service = &supervisor.services[index];
+            match (service.state, service.pid) {
+                (ServiceState::Pending | ServiceState::Backoff, _) => {
+                    supervisor.restart_at.insert(service.name.clone(), now);
+                }
+                (ServiceState::Running, Some(_)) => {
+                    let started = service
+                        .running_pid()
+                        .and_then(process::inspect)
+                        .and_then(|info| SystemTime::now().checked_sub(info.uptime))
+                        .unwrap_or_else(SystemTime::now);
+                    supervisor.begin_checks(index, started);
+                }
+                _ => {}
+            }
+        }
+        Ok(supervisor)
+    }
+
+    /// Persists the registry. A failure is only logged, the services keep
+    /// being supervised from memory.
+    fn save(&self) {
+        if let Err(e) = save_services(&self.services) {
+            println!("{}", e);
+        }
+    }
+
+    fn emit(&mut self, event: Event) {
+        println!("{}", event);
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn find(&self, name: &str) -> Result<usize> {
+        self.services
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| Error::NotFound(name.to_string()))
+    }
+
+    /// Spawns the service at `index` and records its pid
+    fn start(&mut self, index: usize, reason: StartReason) -> Result<u32> {
+        let service = &mut self.services[index];
+        // Taken before the spawn so that readiness sees the very first line of output
+        let started = time::truncate_to_millis(SystemTime::now());
+        match spawn_service(service) {
+            Ok(pid) => {
+                if let Err(e) = history::record_start(&service.name, pid, reason) {
+                    println!("{}", e);
+                }
+                service.pid = Some(pid);
+                service.identity = process::identify(pid);
+                service.state = ServiceState::Running;
+                let name = service.name.clone();
+                self.children.insert(
+                    name.clone(),
+                    Child {
+                        pid,
+                        started: Instant::now(),
+                        stopping: None,
+                    },
+                );
+                self.emit(Event::Started { name, pid });
+                self.services[index].health = Health::default();
+                self.begin_checks(index, started);
+                Ok(pid)
+            }
+            Err(e) => {
+                service.pid = None;
+                service.identity = None;
+                service.state = ServiceState::Failed;
+                println!("{}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Applies the restart policy of a service whose process is gone
+    fn exited(&mut self, index: usize, exit: Option<ExitInfo>) {
+        let service = &mut self.services[index];
+        service.pid = None;
+        service.identity = None;
+        service.forget_empty_session();
+        self.checks.remove(&service.name);
+        if !service.restart.should_restart(exit) {
+            service.state = ServiceState::Exited;
+            return;
+        }
+
+        let now = Instant::now();
+        let name = service.name.clone();
+        let window = service.restart.restart_window;
+        let times = self.restart_times.entry(name.clone()).or_default();
+        while times.front().is_some_and(|time| now.duration_since(*time) > window) {
+            times.pop_front();
+        }
+        if times.len() >= service.restart.max_restarts {
+            service.state = ServiceState::Failed;
+            self.emit(Event::GaveUp { name });
+            return;
+        }
+
+        let delay = INITIAL_BACKOFF
+            .saturating_mul(1 << times.len().min(16))
+            .min(MAX_BACKOFF);
+        times.push_back(now);
+        service.state = ServiceState::Backoff;
+        self.restart_at.insert(name.clone(), now + delay);
+        self.emit(Event::Restarting { name, delay });
+    }
+
+    /// Collects exited children and applies their restart policies
+    fn reap(&mut self) {
+        loop {
+            let mut raw = 0;
+            let pid = unsafe { libc::waitpid(-1, &mut raw, libc::WNOHANG) };
+            if pid <= 0 {
+                return;
+            }
+            let status = ExitStatus::from_raw(raw);
+            let Some(name) = self
+                .children
+                .iter()
+                .find(|(_, child)| child.pid == pid as u32)
+                .map(|(name, _)| name.clone())
+            else {
+                // A log writer or a health check command
+                if let Some(slot) = self.check_commands.get_mut(&(pid as u32)) {
+                    *slot = Some(status);
+                }
+                continue;
+            };
+            let child = self.children.remove(&name).expect("Child disappeared");
+            println!(
+                "Service {} (pid {}) ran for {}s",
+                name,
+                child.pid,
+                child.started.elapsed().as_secs()
+            );
+            self.emit(Event::Exited {
+                name: name.clone(),
+                pid: child.pid,
+                exit: Some(status.into()),
+            });
+            if let Err(e) = history::record_exit(&name, child.pid, Some(status.into())) {
+                println!("{}", e);
+            }
+
+            if let Some(stopping) = child.stopping {
+                let _ = stopping.send(status);
+            } else if let Ok(index) = self.find(&name) {
+                self.exited(index, Some(status.into()));
+                self.save();
+            }
+        }
+    }
+
+    /// Notices when services started by a previous supervisor disappear
+    fn check_adopted(&mut self) {
+        let mut changed = false;
+        for index in 0..self.services.len() {
+            // Whatever a previous run left behind may have exited since
+            changed |= self.services[index].forget_empty_session();
+            let service = &self.services[index];
+            if service.state != ServiceState::Running
+                || self.children.contains_key(&service.name)
+                || self.stopping.contains(&service.name)
+            {
+                continue;
+            }
+            if let Some(pid) = service.pid.filter(|_| service.running_pid().is_none()) {
+                let name = service.name.clone();
+                if let Err(e) = history::record_exit(&name, pid, None) {
+                    println!("{}", e);
+                }
+                self.emit(Event::Exited { name, pid, exit: None });
+                self.exited(index, None);
+                changed = true;
+            }
+        }
+        if changed {
+            self.save();
+        }
+    }
+
+    /// Restarts services whose backoff delay has passed
+    fn restart_due(&mut self) {
+        let now = Instant::now();
+        let due: Vec<String> = self
+            .restart_at
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(name, _)| name.clone())
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+
+        for name in due {
+            self.restart_at.remove(&name);
+            if let Ok(index) = self.find(&name) {
+                let service = &mut self.services[index];
+                let reason = match service.state {
+                    ServiceState::Backoff if service.health.state == HealthState::Unhealthy => {
+                        StartReason::Unhealthy
+                    }
+                    ServiceState::Backoff => StartReason::Exited,
+                    ServiceState::Pending => StartReason::Start,
+                    _ => continue,
+                };
+                if service.state == ServiceState::Backoff {
+                    service.restarts += 1;
+                }
+                service.kill_descendants();
+                let _ = self.start(index, reason);
+            }
+        }
+        self.save();
+    }
+
+    /// Schedules the health checks of the running service at `index`. A service
+    /// without a readiness check is ready right away.
+    fn begin_checks(&mut self, index: usize, started: SystemTime) {
+        let service = &mut self.services[index];
+        let (Some(pid), true) = (service.pid, service.checks.any()) else {
+            return;
+        };
+        let now = Instant::now();
+        let due = if service.health.state == HealthState::Ready {
+            // Adopted from a previous supervisor
+            now + service.checks.interval
+        } else if service.checks.readiness.is_some() {
+            service.health.state = HealthState::Starting;
+            now
+        } else {
+            service.health.state = HealthState::Ready;
+            now + service.checks.interval
+        };
+        let checking = Checking {
+            pid,
+            started,
+            ready_deadline: now + service.checks.ready_timeout,
+            due: Some(due),
+        };
+        self.checks.insert(service.name.clone(), checking);
+    }
+
+    /// Records the result of a health check of the process `pid`
+    fn checked(&mut self, name: &str, pid: u32, result: std::result::Result<(), String>) {
+        // The service may have been restarted or stopped while it was checked
+        let Some(ready_deadline) = self
+            .checks
+            .get(name)
+            .filter(|checking| checking.pid == pid)
+            .map(|checking| checking.ready_deadline)
+        else {
+            return;
+        };
+        let Ok(index) = self.find(name) else {
+            return;
+        };
+        let service = &mut self.services[index];
+        let before = service.health.clone();
+        let now = Instant::now();
+
+        let due = match (service.health.state, result) {
+            (HealthState::Starting, Ok(())) => {
+                service.health.state = HealthState::Ready;
+                service.health.last_error = None;
+                let due = service.checks.liveness.is_some().then(|| now + service.checks.interval);
+                self.emit(Event::Ready { name: name.to_string() });
+                due
+            }
+            (HealthState::Starting, Err(error)) if now >= ready_deadline => {
+                let error = format!(
+                    "Not ready after {}s: {}",
+                    service.checks.ready_timeout.as_secs(),
+                    error
+                );
+                self.unhealthy(index, pid, error);
+                None
+            }
+            (HealthState::Starting, Err(error)) => {
+                service.health.last_error = Some(error);
+                Some(now + READY_INTERVAL)
+            }
+            (_, Ok(())) => {
+                service.health.failures = 0;
+                service.health.last_error = None;
+                Some(now + service.checks.interval)
+            }
+            (_, Err(error)) => {
+                service.health.failures += 1;
+                service.health.last_error = Some(error.clone());
+                if service.health.failures >= service.checks.retries {
+                    self.unhealthy(index, pid, error);
+                    None
+                } else {
+                    Some(now + service.checks.interval)
+                }
+            }
+        };
+        match due {
+            Some(due) => {
+                if let Some(checking) = self.checks.get_mut(name) {
+                    checking.due = Some(due);
+                }
+            }
+            None => {
+                self.checks.remove(name);
+            }
+        }
+        if self.services[index].health != before {
+            self.save();
+        }
+    }
+
+    /// Kills a service that failed its health checks, so that its restart
+    /// policy decides what happens next
+    fn unhealthy(&mut self, index: usize, pid: u32, error: String) {
+        let service = &mut self.services[index];
+        service.health.state = HealthState::Unhealthy;
+        service.health.last_error = Some(error.clone());
+        let name = service.name.clone();
+        let process = ProcessHandle::open(pid, service.identity.as_ref(), service.leads_session());
+        self.emit(Event::Unhealthy { name, error });
+        if let Some(process) = process {
+            thread::spawn(move || kill_unhealthy(process));
+        }
+    }
+
+    /// Prepares to stop a service, so that its exit does not trigger a restart
+    fn begin_stop(&mut self, index: usize) -> StopTarget {
+        let service = &self.services[index];
+        self.restart_at.remove(&service.name);
+        self.checks.remove(&service.name);
+        self.stopping.insert(service.name.clone());
+        if let Some(child) = self.children.get_mut(&service.name) {
+            let (sender, receiver) = mpsc::channel();
+            child.stopping = Some(sender);
+            return StopTarget::Owned(child.pid, service.leads_session(), receiver);
+        }
+        let group = service.leads_session();
+        match service.pid.and_then(|pid| ProcessHandle::open(pid, service.identity.as_ref(), group)) {
+            Some(process) => StopTarget::Adopted(process),
+            None => StopTarget::NotRunning,
+        }
+    }
+
+    /// Hands a service back to the main loop after stopping it, or failing to
+    fn end_stop(&mut self, name: &str) {
+        self.stopping.remove(name);
+        if let Some(child) = self.children.get_mut(name) {
+            child.stopping = None;
+        }
+    }
+}
+
+/// Sends SIGTERM and then SIGKILL, leaving the reaping to the main loop
+fn kill_unhealthy(process: ProcessHandle) {
+    let _ = process.signal(libc::SIGTERM);
+    let deadline = Instant::now() + KILL_TIMEOUT;
+    while Instant::now() < deadline {
+        if !process.is_alive() {
+            return;
+        }
+        thread::sleep(TICK);
+    }
+    let _ = process.signal(libc::SIGKILL);
+}
+
+/// Starts the health checks that are due, each on its own thread
+fn run_due_checks(shared: &Arc<Mutex<Supervisor>>) {
+    let mut supervisor = lock(shared);
+    let now = Instant::now();
+    let due: Vec<String> = supervisor
+        .checks
+        .iter()
+        .filter(|(_, checking)| checking.due.is_some_and(|due| due <= now))
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    for name in due {
+        let Ok(index) = supervisor.find(&name) else {
+            supervisor.checks.remove(&name);
+            continue;
+        };
+        let service = &supervisor.services[index];
+        let starting = service.health.state == HealthState::Starting;
+        let check = if starting {
+            service.checks.readiness.clone()
+        } else {
+            service.checks.liveness.clone()
+        };
+        let Some(check) = check else {
+            supervisor.checks.remove(&name);
+            continue;
+        };
+        let timeout = service.checks.timeout;
+        let interval = service.checks.interval;
+        let logs = service.logs.clone();
+        let cwd = service.cwd.clone();
+        let checking = supervisor.checks.get_mut(&name).expect("Check disappeared");
+        checking.due = None;
+        let pid = checking.pid;
+        // Readiness counts everything since the start, liveness only the last interval
+        let since = if starting {
+            checking.started
+        } else {
+            time::truncate_to_millis(SystemTime::now() - interval)
+        };
+
+        let shared = Arc::clone(shared);
+        thread::spawn(move || {
+            let probe = Probe {
+                logs: logs.as_ref(),
+                since,
+                timeout,
+            };
+            let result = check.probe(&probe, |command| {
+                run_check_command(&shared, command, cwd.as_deref(), timeout)
+            });
+            lock(&shared).checked(&name, pid, result);
+        });
+    }
+}
+
+/// Runs a check command through the shell. The main loop reaps every child,
+/// so the command is registered under the lock and its status picked up there.
+fn run_check_command(
+    shared: &Mutex<Supervisor>,
+    command: &str,
+    cwd: Option<&Path>,
+    timeout: Duration,
+) -> std::result::Result<(), String> {
+    let pid = {
+        let mut supervisor = lock(shared);
+        let mut child = Command::new("sh");
+        child
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if let Some(cwd) = cwd {
+            child.current_dir(cwd);
+        }
+        let pid = child.spawn().map_err(|e| format!("Failed to run {}: {}", command, e))?.id();
+        supervisor.check_commands.insert(pid, None);
+        pid
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        {
+            let mut supervisor = lock(shared);
+            if let Some(Some(status)) = supervisor.check_commands.get(&pid).copied() {
+                supervisor.check_commands.remove(&pid);
+                return match ExitInfo::from(status) {
+                    exit if exit.success() => Ok(()),
+                    exit => Err(format!("{} failed with {}", command, exit)),
+                };
+            }
+            if Instant::now() >= deadline {
+                supervisor.check_commands.remove(&pid);
+                let _ = process::send_signal(pid, libc::SIGKILL);
+                return Err(format!("{} did not finish within {}s", command, timeout.as_secs()));
+            }
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn lock(shared: &Mutex<Supervisor>) -> MutexGuard<'_, Supervisor> {
+    // A panicking client thread must not take the whole supervisor down
+    shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Sends `signal`, waits up to `timeout` and escalates to SIGKILL, without
+/// holding the lock so that the main loop can reap the process meanwhile
+fn wait_for_stop(
+    target: StopTarget,
+    signal: libc::c_int,
+    timeout: Duration,
+) -> Result<(StopPath, Option<ExitInfo>)> {
+    let signal_failed = |pid: u32| move |e| Error::io(format!("Failed to signal process {}", pid), e);
+    match target {
+        StopTarget::NotRunning => Ok((StopPath::NotRunning, None)),
+        StopTarget::Adopted(process) => {
+            let outcome = process::terminate(&process, signal, timeout).map_err(signal_failed(process.pid()))?;
+            Ok((outcome.path, outcome.status.map(ExitInfo::from)))
+        }
+        // Our child cannot be replaced by another process before we reap it
+        StopTarget::Owned(pid, group, receiver) => {
+            let send = |signal| {
+                if group {
+                    process::signal_group(pid, signal)
+                } else {
+                    process::send_signal(pid, signal)
+                }
+            };
+            // Only a reaped child is gone, and the reaper hands its status over
+            let reaped = |path| -> Result<(StopPath, Option<ExitInfo>)> {
+                Ok((path, receiver.recv_timeout(KILL_TIMEOUT).ok().map(ExitInfo::from)))
+            };
+            match send(signal) {
+                Err(e) if e.raw_os_error() == Some(libc::ESRCH) => return reaped(StopPath::NotRunning),
+                result => result.map_err(signal_failed(pid))?,
+            }
+            match receiver.recv_timeout(timeout) {
+                Ok(status) => return Ok((StopPath::Graceful(signal), Some(status.into()))),
+                Err(RecvTimeoutError::Disconnected) => return Ok((StopPath::Graceful(signal), None)),
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            match send(libc::SIGKILL) {
+                // It exited after all, just later than asked to
+                Err(e) if e.raw_os_error() == Some(libc::ESRCH) => return reaped(StopPath::Graceful(signal)),
+                result => result.map_err(signal_failed(pid))?,
+            }
+            match receiver.recv_timeout(KILL_TIMEOUT) {
+                Ok(status) => Ok((StopPath::Killed, Some(status.into()))),
+                Err(RecvTimeoutError::Disconnected) => Ok((StopPath::Killed, None)),
+                Err(RecvTimeoutError::Timeout) => {
+                    Err(Error::Other(format!("Process {} survived SIGKILL", pid)))
+                }
+            }
+        }
+    }
+}
+
+fn handle_start(shared: &Mutex<Supervisor>, mut service: Service, env: BTreeMap<String, String>) -> Result<Response> {
+    validate_name(&service.name).map_err(Error::Invalid)?;
+    let mut supervisor = lock(shared);
+    if supervisor.find(&service.name).is_ok() {
+        return Err(Error::AlreadyRunning(service.name));
+    }
+    for dependency in &service.depends_on {
+        let ready = supervisor.find(dependency).is_ok_and(|index| {
+            let dependency = &supervisor.services[index];
+            dependency.state == ServiceState::Running && dependency.health.is_ready()
+        });
+        if !ready {
+            return Err(Error::NotReady(format!(
+                "Service {} depends on {} which is not running and ready",
+                service.name, dependency
+            )));
+        }
+    }
+    // Rejected here rather than registered as a service that failed to start
+    service.credentials()?;
+    service.caller_env = Some(env);
+    service.pid = None;
+    service.identity = None;
+    service.restarts = 0;
+    supervisor.restart_times.remove(&service.name);
+    supervisor.services.push(service);
+    let index = supervisor.services.len() - 1;
+    let started = supervisor.start(index, StartReason::Start);
+    supervisor.save();
+    Ok(Response::Started { pid: started? })
+}
+
+/// Gives what a stopped service left behind until `deadline` to exit after
+/// `signal`, then kills it and removes its cgroup once it is empty
+fn remove_descendants(service: &Service, signal: libc::c_int, deadline: Instant) {
+    // The process group already got the signal with the main process
+    let group = service.pid.filter(|_| service.leads_session());
+    for (pid, _) in service.descendants() {
+        if group.is_none_or(|group| unsafe { libc::getpgid(pid as libc::pid_t) } != group as libc::pid_t) {
+            let _ = process::send_signal(pid, signal);
+        }
+    }
+    while !service.descendants().is_empty() && Instant::now() < deadline {
+        thread::sleep(TICK);
+    }
+    service.kill_descendants();
+    let Some(path) = &service.cgroup_path else {
+        return;
+    };
+    let deadline = Instant::now() + KILL_TIMEOUT;
+    while !cgroup::members(path).is_empty() && Instant::now() < deadline {
+        thread::sleep(TICK);
+    }
+    cgroup::remove(path);
+}
+
+/// Completes the history of a stopped process that was not our child, the
+/// exits of children are recorded when they are reaped
+fn record_stop(service: &Service, exit: Option<ExitInfo>) {
+    if let Some(pid) = service.pid {
+        if let Err(e) = history::record_exit(&service.name, pid, exit) {
+            println!("{}", e);
+        }
+    }
+}
+
+fn handle_stop(shared: &Mutex<Supervisor>, name: &str, signal: libc::c_int, timeout: Duration) -> Result<Response> {
+    let (target, service) = {
+        let mut supervisor = lock(shared);
+        let index = supervisor.find(name)?;
+        (supervisor.begin_stop(index), supervisor.services[index].clone())
+    };
+
+    let deadline = Instant::now() + timeout;
+    let (path, exit) = match wait_for_stop(target, signal, timeout) {
+        Ok(stopped) => stopped,
+        Err(e) => {
+            // A process that could not be stopped stays tracked
+            lock(shared).end_stop(name);
+            return Err(e);
+        }
+    };
+    record_stop(&service, exit);
+    remove_descendants(&service, signal, deadline);
+
+    let mut supervisor = lock(shared);
+    supervisor.end_stop(name);
+    if let Ok(index) = supervisor.find(name) {
+        supervisor.services.remove(index);
+        supervisor.save();
+    }
+    supervisor.emit(Event::Stopped { name: name.to_string() });
+    Ok(Response::Stopped { path, exit })
+}
+
+fn handle_restart(shared: &Mutex<Supervisor>, name: &str, signal: libc::c_int, timeout: Duration) -> Result<Response> {
+    let (target, service) = {
+        let mut supervisor = lock(shared);
+        let index = supervisor.find(name)?;
+        (supervisor.begin_stop(index), supervisor.services[index].clone())
+    };
+
+    let deadline = Instant::now() + timeout;
+    let (path, exit) = match wait_for_stop(target, signal, timeout) {
+        Ok(stopped) => stopped,
+        Err(e) => {
+            lock(shared).end_stop(name);
+            return Err(e);
+        }
+    };
+    record_stop(&service, exit);
+    remove_descendants(&service, signal, deadline);
+
+    let mut supervisor = lock(shared);
+    supervisor.end_stop(name);
+    // The service may have been stopped by someone else in the meantime
+    let index = supervisor.find(name)?;
+    supervisor.restart_times.remove(name);
+    supervisor.services[index].restarts = 0;
+    let started = supervisor.start(index, StartReason::Restart);
+    supervisor.save();
+    Ok(Response::Restarted { path, exit, pid: started? })
+}
+
+fn handle_reload(shared: &Mutex<Supervisor>, name: &str) -> Result<Response> {
+    let mut supervisor = lock(shared);
+    let service = &supervisor.services[supervisor.find(name)?];
+    let signal = service.reload_signal;
+    // Only the main process, it is up to the service to tell its children
+    let process = service
+        .pid
+        .and_then(|pid| ProcessHandle::open(pid, service.identity.as_ref(), false))
+        .ok_or_else(|| Error::NotReady(format!("Service {} is not running", name)))?;
+    process.signal(signal).map_err(|e| {
+        Error::io(format!("Failed to send {} to {}", process::signal_name(signal), process.pid()), e)
+    })?;
+    supervisor.emit(Event::Reloaded { name: name.to_string(), signal });
+    Ok(Response::Reloaded { pid: process.pid(), signal })
+}
+
+fn handle_status(shared: &Mutex<Supervisor>, name: Option<String>) -> Result<Response> {
+    let supervisor = lock(shared);
+    match name {
+        Some(name) => {
+            let index = supervisor.find(&name)?;
+            Ok(Response::Services(vec![supervisor.services[index].clone()]))
+        }
+        None => Ok(Response::Services(supervisor.services.clone())),
+    }
+}
+
+fn handle_logs(shared: &Mutex<Supervisor>, name: &str) -> Result<Response> {
+    let supervisor = lock(shared);
+    let index = supervisor.find(name)?;
+    Ok(Response::Logs(supervisor.services[index].logs.clone()))
+}
+
+/// Waits until a service is running and passed its readiness check, or
+/// until it is given up on
+fn handle_wait_ready(shared: &Mutex<Supervisor>, name: &str) -> Result<Response> {
+    loop {
+        {
+            let supervisor = lock(shared);
+            let service = &supervisor.services[supervisor.find(name)?];
+            match (service.state, service.health.state) {
+                (ServiceState::Running, _) if service.health.is_ready() => return Ok(Response::Ready),
+                (_, HealthState::Unhealthy) => {
+                    let error = service.health.last_error.clone().unwrap_or_default();
+                    return Err(Error::NotReady(format!(
+                        "Service {} did not become ready: {}",
+                        name, error
+                    )));
+                }
+                (ServiceState::Exited | ServiceState::Failed, _) => {
+                    return Err(Error::NotReady(format!("Service {} exited before it was ready", name)));
+                }
+                _ => {}
+            }
+        }
+        thread::sleep(TICK);
+    }
+}
+
+/// Streams events to a subscribed client until it disconnects
+fn handle_subscribe(shared: &Mutex<Supervisor>, stream: &mut UnixStream) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    lock(shared).subscribers.push(sender);
+    protocol::write_message(stream, Response::Subscribed)?;
+    for event in receiver {
+        protocol::write_message(stream, Response::Event(event))?;
+    }
+    Ok(())
+}
+
+fn handle_connection(shared: &Mutex<Supervisor>, stream: UnixStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let request = match protocol::read_message::<Request>(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(error) => return protocol::write_message(&mut writer, Response::Error(error)),
+        };
+        let result = match request {
+            Request::Start { service, env } => handle_start(shared, *service, env),
+            Request::Stop { name, signal, timeout } => handle_stop(shared, &name, signal, timeout),
+            Request::Restart { name, signal, timeout } => {
+                handle_restart(shared, &name, signal, timeout)
+            }
+            Request::Reload { name } => handle_reload(shared, &name),
+            Request::Status { name } => handle_status(shared, name),
+            Request::Logs { name } => handle_logs(shared, &name),
+            Request::WaitReady { name } => handle_wait_ready(shared, &name),
+            Request::Subscribe => return handle_subscribe(shared, &mut writer),
+        };
+        let response = result.unwrap_or_else(Response::Error);
+        protocol::write_message(&mut writer, response)?;
+    }
+}
+
+fn listen(shared: Arc<Mutex<Supervisor>>, listener: UnixListener) {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let shared = Arc::clone(&shared);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(&shared, stream) {
+                println!("Client connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Runs the supervisor until it receives SIGTERM or SIGINT
+pub fn run() -> Result<()> {
+    let _lock = match lock_instance() {
+        Ok(lock) => lock,
+        Err(_) => {
+            println!("Another supervisor is already running");
+            return Ok(());
+        }
+    };
+    // Before anything is forked, the log writers and checks end up in the leaf too
+    if let Err(e) = cgroup::enter_leaf() {
+        println!("Staying in the cgroup the supervisor was started in: {}", e);
+    }
+    let pid_path = get_pid_path();
+    fs::write(&pid_path, std::process::id().to_string())
+        .map_err(|e| Error::io(format!("Failed to write {}", pid_path.display()), e))?;
+    let supervisor = Supervisor::load()?;
+
+    // Holding the lock means any existing socket is stale
+    let socket_path = get_socket_path();
+    let _ = fs::remove_file(&socket_path);
+    let listener = UnixListener::bind(&socket_path)
+        .and_then(|listener| {
+            fs::set_permissions(&socket_path, fs::Permissions::from_mode(0o600))?;
+            Ok(listener)
+        })
+        .map_err(|e| Error::io(format!("Failed to bind {}", socket_path.display()), e))?;
+
+    unsafe {
+        libc::signal(libc::SIGTERM, on_shutdown as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_shutdown as *const () as libc::sighandler_t);
+    }
+    println!("Supervisor started with pid {}", std::process::id());
+
+    let shared = Arc::new(Mutex::new(supervisor));
+    let listener_shared = Arc::clone(&shared);
+    thread::spawn(move || listen(listener_shared, listener));
+
+    let mut adopted_checked = Instant::now();
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        {
+            let mut supervisor = lock(&shared);
+            supervisor.reap();
+            supervisor.restart_due();
+            if adopted_checked.elapsed() >= ADOPTED_CHECK_INTERVAL {
+                supervisor.check_adopted();
+                adopted_checked = Instant::now();
+            }
+        }
+        run_due_checks(&shared);
+        thread::sleep(TICK);
+    }
+
+    // The services keep running, a new supervisor picks them up again
+    println!("Supervisor shutting down");
+    let _ = fs::remove_file(&socket_path);
+    let _ = fs::remove_file(&pid_path);
+    Ok(())
+}
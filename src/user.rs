@@ -0,0 +1,135 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::path::PathBuf;
+
+/// Who a service runs as, with the names resolved to ids
+pub struct Credentials {
+    pub uid: Option<libc::uid_t>,
+    pub gid: Option<libc::gid_t>,
+    pub groups: Vec<libc::gid_t>,
+    /// Name and home of the user, to set `USER` and `HOME`
+    pub user: Option<(String, PathBuf)>,
+}
+
+struct Passwd {
+    name: String,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    home: PathBuf,
+}
+
+/// Resolves a user, group and supplementary groups given by name or id.
+/// Without explicit supplementary groups those of the user are used.
+pub fn resolve(user: Option<&str>, group: Option<&str>, groups: &[String]) -> Result<Credentials, String> {
+    let passwd = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => passwd.as_ref().map(|passwd| passwd.gid),
+    };
+    let groups = match (&passwd, gid) {
+        _ if !groups.is_empty() => groups.iter().map(|g| lookup_group(g)).collect::<Result<_, _>>()?,
+        (Some(passwd), Some(gid)) => user_groups(&passwd.name, gid)?,
+        // Never keep the supplementary groups of root
+        _ => Vec::new(),
+    };
+    Ok(Credentials {
+        uid: passwd.as_ref().map(|passwd| passwd.uid),
+        gid,
+        groups,
+        user: passwd.map(|passwd| (passwd.name, passwd.home)),
+    })
+}
+
+/// Drops to the credentials, groups first as that needs root. Only makes
+/// system calls so it is safe after fork.
+pub fn drop_privileges(credentials: &Credentials) -> io::Result<()> {
+    let check = |result: libc::c_int| {
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    };
+    let groups = &credentials.groups;
+    check(unsafe { libc::setgroups(groups.len(), groups.as_ptr()) })?;
+    if let Some(gid) = credentials.gid {
+        check(unsafe { libc::setgid(gid) })?;
+    }
+    if let Some(uid) = credentials.uid {
+        check(unsafe { libc::setuid(uid) })?;
+    }
+    Ok(())
+}
+
+/// Room for the strings of a passwd or group entry
+const BUFFER_SIZE: usize = 16384;
+
+fn lookup_user(user: &str) -> Result<Passwd, String> {
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+    let status = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid_r(uid, &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) },
+        Err(_) => {
+            let name = CString::new(user).map_err(|_| format!("Invalid user name {}", user))?;
+            unsafe { libc::getpwnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) }
+        }
+    };
+    if status != 0 {
+        return Err(format!("Failed to look up user {}: {}", user, io::Error::from_raw_os_error(status)));
+    }
+    if result.is_null() {
+        return Err(format!("Unknown user {}", user));
+    }
+    let string = |ptr: *const libc::c_char| unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned();
+    Ok(Passwd {
+        name: string(entry.pw_name),
+        uid: entry.pw_uid,
+        gid: entry.pw_gid,
+        home: PathBuf::from(string(entry.pw_dir)),
+    })
+}
+
+/// A group id, numeric ids need no entry in the group database
+fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| format!("Invalid group name {}", group))?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+    let status =
+        unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if status != 0 {
+        return Err(format!("Failed to look up group {}: {}", group, io::Error::from_raw_os_error(status)));
+    }
+    if result.is_null() {
+        return Err(format!("Unknown group {}", group));
+    }
+    Ok(entry.gr_gid)
+}
+
+/// The groups the user is a member of, as login would set them
+fn user_groups(user: &str, gid: libc::gid_t) -> Result<Vec<libc::gid_t>, String> {
+    let name = CString::new(user).map_err(|_| format!("Invalid user name {}", user))?;
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let found = unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if found >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        // Too small, `count` now holds the number of groups
+        groups.resize((count as usize).max(groups.len() * 2), 0);
+    }
+}
+
+/// The home directory of the current user, from `HOME` or else the user database
+pub fn home_dir() -> Option<PathBuf> {
+    match std::env::var_os("HOME") {
+        Some(home) if !home.is_empty() => Some(PathBuf::from(home)),
+        _ => lookup_user(&unsafe { libc::geteuid() }.to_string()).ok().map(|passwd| passwd.home),
+    }
+}
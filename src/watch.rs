@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cargo::{self, CargoTarget};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long the sources have to stay unchanged before rebuilding
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Directories that never contain sources worth rebuilding for
+const IGNORED_DIRS: [&str; 2] = ["target", "node_modules"];
+
+/// The directories whose files affect the build of `target`: the directory of
+/// its package and of every path dependency
+pub fn watched_dirs(target: &CargoTarget) -> Result<Vec<PathBuf>, String> {
+    let metadata = cargo::metadata(Some(&target.manifest_path))?;
+    let package = metadata
+        .packages
+        .iter()
+        .find(|package| package.name == target.package)
+        .ok_or_else(|| format!("Package {} not found", target.package))?;
+
+    let mut dirs: Vec<PathBuf> = package.manifest_path.parent().map(Path::to_path_buf).into_iter().collect();
+    dirs.extend(package.dependencies.iter().filter_map(|dep| dep.path.clone()));
+    dirs.sort();
+    dirs.dedup();
+    Ok(dirs)
+}
+
+fn snapshot_dir(dir: &Path, files: &mut HashMap<PathBuf, SystemTime>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            if !name.starts_with('.') && !IGNORED_DIRS.contains(&name.as_ref()) {
+                snapshot_dir(&path, files);
+            }
+        } else if let Ok(modified) = metadata.modified() {
+            files.insert(path, modified);
+        }
+    }
+}
+
+/// The modification time of every file under `dirs`
+fn snapshot(dirs: &[PathBuf]) -> HashMap<PathBuf, SystemTime> {
+    let mut files = HashMap::new();
+    for dir in dirs {
+        snapshot_dir(dir, &mut files);
+    }
+    files
+}
+
+/// Polls `dirs` forever and calls `on_change` once the files stopped changing
+pub fn watch(dirs: &[PathBuf], mut on_change: impl FnMut()) {
+    let mut last = snapshot(dirs);
+    let mut changed_at: Option<Instant> = None;
+    loop {
+        thread::sleep(POLL_INTERVAL.min(DEBOUNCE));
+        let current = snapshot(dirs);
+        if current != last {
+            last = current;
+            changed_at = Some(Instant::now());
+        } else if changed_at.is_some_and(|at| at.elapsed() >= DEBOUNCE) {
+            changed_at = None;
+            on_change();
+            // Whatever the build wrote is not a change to react to
+            last = snapshot(dirs);
+        }
+    }
+}